@@ -0,0 +1,126 @@
+//! The per-packet work on both ends, and whole transfers over the simulator, to catch regressions in the
+//! CPU cost that the huge performance test is sensitive to. Run with `cargo bench`.
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use PROJECT3::config::ConnectionConfig;
+use PROJECT3::protocol::{AckFrame, DataFrame, Frame, FrameType, Header, Packet, PacketType, ReceivedPacket, SentPacket, State};
+use PROJECT3::sim::{LinkConfig, Simulation};
+
+const OUTSTANDING: u64 = 5000;
+
+fn peer() -> SocketAddr {
+    "127.0.0.1:28899".parse().unwrap()
+}
+
+fn data_packet() -> Packet {
+    let dataframe = DataFrame { end: false, byte_offset: 123456, data: vec![0x5a; 1400] };
+    Packet {
+        header: Header { packet_type: PacketType::NORM, packet_num: 1000 },
+        frames: vec![Frame { frame_type: FrameType::DATA, frame_data: dataframe.serialize() }],
+    }
+}
+
+fn ack_packet() -> Packet {
+    let ackframe = AckFrame { largest_ack: 100000, ack_delay: 5000000, ack_ranges: [3, 1].repeat(100), ecn_counts: None };
+    Packet {
+        header: Header { packet_type: PacketType::NORM, packet_num: 1000 },
+        frames: vec![Frame { frame_type: FrameType::ACK, frame_data: ackframe.serialize() }],
+    }
+}
+
+/// A sender with `OUTSTANDING` data packets in flight, all sent at `now`, and the largest ACKed packet
+/// `acked` packets into them.
+fn sender_with_outstanding(now: Instant, acked: u64) -> State {
+    let mut state = State::new(1, Some(peer()), ConnectionConfig::default());
+    for packet_num in 1..=OUTSTANDING {
+        let sent_packet = SentPacket { packet_num, size: 1472, time_sent: now, in_flight: true, is_ack_only: false, is_app_limited: false, is_ecn_marked: false };
+        state.sent_packets.insert(packet_num, sent_packet);
+        state.bytes_in_flight += 1472;
+    }
+    state.congestion_window = state.bytes_in_flight;
+    state.last_packet_num = OUTSTANDING;
+    state.sent_largest_ACKed = acked;
+    state.established = true;
+    state
+}
+
+fn serialize(c: &mut Criterion) {
+    let mut group = c.benchmark_group("codec");
+    for (name, packet) in [("data", data_packet()), ("ack", ack_packet())].iter() {
+        let bytes = packet.serialize();
+        group.throughput(Throughput::Bytes(bytes.len() as u64));
+        group.bench_function(format!("serialize {}", name), |b| b.iter(|| packet.serialize()));
+        group.bench_function(format!("deserialize {}", name), |b| b.iter(|| Packet::deserialize(&bytes)));
+    }
+    group.finish();
+}
+
+fn generate_ackframe(c: &mut Criterion) {
+    let now = Instant::now();
+    let mut group = c.benchmark_group("generate_ackframe");
+    // Runs of received packets separated by gaps of the given length.
+    for gap in [1, 10, 100].iter() {
+        let mut state = State::new(1, Some(peer()), ConnectionConfig::default());
+        state.connected = true;
+        state.ack_starting_packet_num = 1;
+        let mut packet_num = 1;
+        while packet_num <= OUTSTANDING {
+            for _ in 0..10 {
+                state.received_packets.insert(packet_num, ReceivedPacket { packet_num, time_received: now, ack_sent: false, is_ack_only: false });
+                state.received_largest = packet_num;
+                packet_num += 1;
+            }
+            packet_num += gap;
+        }
+        group.bench_function(format!("gap {}", gap), |b| b.iter(|| state.generate_ackframe(now)));
+    }
+    group.finish();
+}
+
+fn get_new_acked_packets(c: &mut Criterion) {
+    let now = Instant::now();
+    let ackframe = AckFrame { largest_ack: OUTSTANDING, ack_delay: 0, ack_ranges: [3, 1].repeat(OUTSTANDING as usize / 4), ecn_counts: None };
+    c.bench_function("get_new_acked_packets", |b| {
+        b.iter_batched(|| sender_with_outstanding(now, 0), |mut state| state.get_new_acked_packets(&ackframe), BatchSize::LargeInput)
+    });
+}
+
+fn detect_packet_lost(c: &mut Criterion) {
+    let now = Instant::now();
+    let mut group = c.benchmark_group("detect_packet_lost");
+    // Nothing is lost yet, the common case on every timeout: a scan of the outstanding packets.
+    let mut state = sender_with_outstanding(now, 0);
+    group.bench_function("none lost", |b| b.iter(|| state.detect_packet_lost(now)));
+    // Half of them fall behind the reorder threshold and are declared lost.
+    group.bench_function("half lost", |b| {
+        b.iter_batched(|| sender_with_outstanding(now, OUTSTANDING / 2), |mut state| state.detect_packet_lost(now), BatchSize::LargeInput)
+    });
+    group.finish();
+}
+
+fn transfer(c: &mut Criterion) {
+    let mut group = c.benchmark_group("transfer");
+    group.sample_size(10);
+    for (name, size, link) in [
+        ("100 KB 1 Mb/s", 100000, LinkConfig { bandwidth: 1.0, ..Default::default() }),
+        ("1 MB 5 Mb/s", 1000000, LinkConfig { bandwidth: 5.0, ..Default::default() }),
+        ("100 KB 10 Mb/s 10% drop", 100000, LinkConfig { bandwidth: 10.0, drop: 10.0, ..Default::default() }),
+    ]
+    .iter()
+    {
+        let data: Vec<u8> = (0..*size).map(|i| (i * 31 % 251) as u8).collect();
+        group.throughput(Throughput::Bytes(*size as u64));
+        group.bench_function(*name, |b| {
+            b.iter(|| {
+                let result = Simulation::new(data.clone(), link.clone(), 0).run(Duration::from_secs(60));
+                assert!(result.data_matches(&data));
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, serialize, generate_ackframe, get_new_acked_packets, detect_packet_lost, transfer);
+criterion_main!(benches);
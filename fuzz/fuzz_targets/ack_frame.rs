@@ -0,0 +1,12 @@
+//! `AckFrame::deserialize` on arbitrary frame data: it must not panic, and whatever it accepts has to survive a
+//! serialize and deserialize unchanged.
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use PROJECT3::protocol::AckFrame;
+
+fuzz_target!(|bytes: &[u8]| {
+    if let Some(ackframe) = AckFrame::deserialize(&bytes.to_vec()) {
+        assert_eq!(AckFrame::deserialize(&ackframe.serialize()), Some(ackframe));
+    }
+});
@@ -0,0 +1,202 @@
+//! Drives a sender and a receiver through a network whose every decision is taken from the fuzzer input:
+//! which datagram gets delivered, dropped, duplicated or reordered, when the clock moves, and which forged
+//! packets are injected: ACK, DATA and CLOSE, as INIT or not, numbered from 0 up. Checks that nothing panics, that the in-flight accounting and the congestion window stay consistent, and that
+//! once the network is left alone the data gets delivered.
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use PROJECT3::clock::{Clock, MockClock};
+use PROJECT3::config::ConnectionConfig;
+use PROJECT3::protocol::{DataFrame, Frame, FrameType, Header, Packet, PacketType, State};
+use PROJECT3::sim::{Receiver, Sender};
+
+struct Input<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Input<'a> {
+    fn byte(&mut self) -> Option<u8> {
+        let (first, rest) = self.bytes.split_first()?;
+        self.bytes = rest;
+        Some(*first)
+    }
+    fn u16(&mut self) -> Option<u16> {
+        Some(u16::from(self.byte()?) << 8 | u16::from(self.byte()?))
+    }
+    fn u64(&mut self) -> Option<u64> {
+        Some(u64::from(self.u16()?) << 48 | u64::from(self.u16()?) << 32 | u64::from(self.u16()?) << 16 | u64::from(self.u16()?))
+    }
+    /// Any value, the largest ones more often than chance would pick them.
+    fn any_u64(&mut self) -> u64 {
+        match self.byte().unwrap_or(0) % 4 {
+            0 => u64::MAX,
+            1 => u64::MAX - self.byte().unwrap_or(0) as u64,
+            _ => self.u64().unwrap_or(u64::MAX),
+        }
+    }
+    /// A packet number just past `next`, or sometimes any below it, down to 0 which no real packet carries, or
+    /// anywhere up to `u64::MAX`.
+    fn packet_num(&mut self, next: u64) -> u64 {
+        match self.byte().unwrap_or(1) % 8 {
+            0 => self.u16().unwrap_or(0) as u64 % (next + 4),
+            1 => self.any_u64(),
+            extra => next + extra as u64 % 4,
+        }
+    }
+    fn packet_type(&mut self) -> PacketType {
+        if self.byte().unwrap_or(1) % 4 == 0 { PacketType::INIT } else { PacketType::NORM }
+    }
+    /// An ACK range length: mostly a few packets, sometimes of any magnitude the encoding allows.
+    fn ack_range(&mut self) -> u64 {
+        match self.byte().unwrap_or(0) {
+            byte if byte & 0x80 == 0 => byte as u64 % 8 + 1,
+            byte => self.u64().unwrap_or(u64::MAX) >> (byte % 64),
+        }
+    }
+}
+
+/// Datagrams on their way in one direction, in the order they will be delivered.
+type Wire = VecDeque<Vec<u8>>;
+
+fn check_invariants(state: &State) {
+    let in_flight: usize = state.sent_packets.values().map(|sent_packet| sent_packet.size).sum();
+    assert_eq!(state.bytes_in_flight, in_flight, "bytes in flight out of sync with the sent packets");
+    assert!(state.congestion_window >= state.config.minimum_window, "congestion window {} below the minimum", state.congestion_window);
+}
+
+fn forged_packet(packet_type: PacketType, packet_num: u64, frame: Frame) -> Vec<u8> {
+    Packet { header: Header { packet_type, packet_num }, frames: vec![frame] }.serialize()
+}
+
+/// The varint encoding of the frames, written out here so ACK ranges can take values `AckFrame` cannot hold.
+fn varint(output: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        output.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    output.push(value as u8);
+}
+
+fn run(bytes: &[u8]) {
+    let mut input = Input { bytes };
+    let size = match input.u16() {
+        Some(size) => size as usize % 30000 + 1,
+        None => return,
+    };
+    let data: Vec<u8> = (0..size).map(|i| (i * 31 % 251) as u8).collect();
+    let clock = MockClock::new();
+    let sender_addr: SocketAddr = "127.0.0.1:29170".parse().unwrap();
+    let receiver_addr: SocketAddr = "127.0.0.1:28899".parse().unwrap();
+    let initial_packet_nums = (input.byte().unwrap_or(1).max(1), input.byte().unwrap_or(1).max(1));
+    // Smaller than most inputs, so the buffer is released and refilled as ACKs come in.
+    let config = ConnectionConfig::builder().send_buffer_capacity(8192).build().unwrap();
+    let mut sender = Sender::new(State::with_clock(initial_packet_nums.0 as u64, Some(receiver_addr), config, Arc::new(clock.clone())), data.clone());
+    let mut receiver = Receiver::new(State::with_clock(initial_packet_nums.1 as u64, None, ConnectionConfig::default(), Arc::new(clock.clone())));
+    // The receiver reports no marks, so ECN only stays on until the first real ACK, forged counts can come first.
+    sender.state.ecn_enabled = input.byte().unwrap_or(0) % 2 == 1;
+    let mut to_receiver = Wire::new();
+    let mut to_sender = Wire::new();
+    let mut forged = false;
+
+    sender.start();
+    while let Some(op) = input.byte() {
+        let now = clock.now();
+        match op % 13 {
+            0 => if let Some(datagram) = to_receiver.pop_front() { receiver.handle_datagram(&datagram, sender_addr, now); },
+            1 => if let Some(datagram) = to_sender.pop_front() { sender.handle_datagram(&datagram, receiver_addr, now); },
+            2 => { to_receiver.pop_front(); }
+            3 => { to_sender.pop_front(); }
+            4 => if let Some(datagram) = to_receiver.front().cloned() { to_receiver.push_back(datagram); },
+            5 => if let Some(datagram) = to_sender.front().cloned() { to_sender.push_back(datagram); },
+            6 => if to_receiver.len() > 1 { to_receiver.swap(0, 1); },
+            7 => if to_sender.len() > 1 { to_sender.swap(0, 1); },
+            8 => clock.advance(Duration::from_millis(input.byte().unwrap_or(0) as u64)),
+            9 => if let Some(timeout) = sender.next_timeout().into_iter().chain(receiver.next_timeout()).min() { clock.advance_to(timeout); },
+            10 => {
+                // ACK for packets around the ones the sender has used, with ranges and ECN counts of any size.
+                forged = true;
+                let span = sender.state.last_packet_num - sender.state.initial_sent_packet_num + 8;
+                let largest_ack = match input.byte().unwrap_or(1) % 8 {
+                    0 => input.any_u64(),
+                    _ => sender.state.initial_sent_packet_num + input.u16().unwrap_or(0) as u64 % span,
+                };
+                let mut ack_ranges = vec![input.ack_range()];
+                while ack_ranges.len() < 4 && input.byte().unwrap_or(0) % 2 == 1 { ack_ranges.push(input.ack_range()); }
+                let mut ack = Vec::new();
+                varint(&mut ack, largest_ack);
+                varint(&mut ack, input.byte().unwrap_or(0) as u64 * 100_000);
+                varint(&mut ack, ack_ranges.len() as u64);
+                for range in ack_ranges { varint(&mut ack, range); }
+                if input.byte().unwrap_or(0) % 2 == 1 {
+                    for _ in 0..3 { varint(&mut ack, input.u64().unwrap_or(0) >> (input.byte().unwrap_or(0) % 64)); }
+                }
+                let packet_num = input.packet_num(receiver.state.last_packet_num + 1);
+                to_sender.push_back(forged_packet(input.packet_type(), packet_num, Frame { frame_type: FrameType::ACK, frame_data: ack }));
+            }
+            12 => {
+                // CLOSE in either direction, before, during or after the transfer.
+                forged = true;
+                let close = Frame { frame_type: FrameType::CLOSE, frame_data: vec![0] };
+                if input.byte().unwrap_or(0) % 2 == 0 {
+                    let packet_num = input.packet_num(sender.state.last_packet_num + 1);
+                    to_receiver.push_back(forged_packet(input.packet_type(), packet_num, close));
+                } else {
+                    let packet_num = input.packet_num(receiver.state.last_packet_num + 1);
+                    to_sender.push_back(forged_packet(input.packet_type(), packet_num, close));
+                }
+            }
+            _ => {
+                // DATA carrying the right bytes, at an offset and length the sender would not have chosen, or at
+                // any offset at all.
+                forged = true;
+                let offset = input.u16().unwrap_or(0) as usize % size;
+                let length = (input.u16().unwrap_or(0) as usize % 1400 + 1).min(size - offset);
+                let mut dataframe = DataFrame { end: offset + length == size, byte_offset: offset as u64, data: data[offset..offset + length].to_vec() };
+                // Or the same bytes at any offset, including ones whose end overflows.
+                if input.byte().unwrap_or(1) % 8 == 0 { dataframe.byte_offset = input.any_u64(); }
+                let packet_num = input.packet_num(receiver.state.received_largest + 1);
+                to_receiver.push_back(forged_packet(input.packet_type(), packet_num, Frame { frame_type: FrameType::DATA, frame_data: dataframe.serialize() }));
+            }
+        }
+        let now = clock.now();
+        receiver.step(now);
+        sender.step(now);
+        while let Some(transmit) = sender.state.poll_transmit(now) { to_receiver.push_back(transmit.contents); }
+        while let Some(transmit) = receiver.state.poll_transmit(now) { to_sender.push_back(transmit.contents); }
+        check_invariants(&sender.state);
+        check_invariants(&receiver.state);
+        if let Some(delivered) = &receiver.delivered { assert!(&delivered[..] == &data[..], "delivered data differs"); }
+    }
+
+    // Leave the network alone, everything sent from now on arrives. Forged ACKs can acknowledge data that never
+    // arrived, and forged DATA takes packet numbers the sender uses too, so the real packet is dropped as a
+    // duplicate yet acknowledged. Delivery is only checked without them.
+    let deadline = clock.now() + Duration::from_secs(120);
+    while receiver.delivered == None && clock.now() < deadline {
+        let now = clock.now();
+        while let Some(datagram) = to_receiver.pop_front() { receiver.handle_datagram(&datagram, sender_addr, now); }
+        while let Some(datagram) = to_sender.pop_front() { sender.handle_datagram(&datagram, receiver_addr, now); }
+        receiver.step(now);
+        sender.step(now);
+        while let Some(transmit) = sender.state.poll_transmit(now) { to_receiver.push_back(transmit.contents); }
+        while let Some(transmit) = receiver.state.poll_transmit(now) { to_sender.push_back(transmit.contents); }
+        check_invariants(&sender.state);
+        check_invariants(&receiver.state);
+        if to_receiver.is_empty() && to_sender.is_empty() {
+            match sender.next_timeout().into_iter().chain(receiver.next_timeout()).min() {
+                Some(timeout) if timeout > now => clock.advance_to(timeout),
+                Some(_) => clock.advance(Duration::from_millis(1)),
+                None => break,
+            }
+        }
+    }
+    if !forged {
+        assert!(receiver.delivered.as_ref() == Some(&data), "data not delivered after the network recovered");
+    }
+}
+
+fuzz_target!(|bytes: &[u8]| run(bytes));
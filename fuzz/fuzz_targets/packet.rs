@@ -0,0 +1,12 @@
+//! `Packet::deserialize` on arbitrary datagrams: it must not panic, and whatever it accepts has to survive a
+//! serialize and deserialize unchanged.
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use PROJECT3::protocol::Packet;
+
+fuzz_target!(|bytes: &[u8]| {
+    if let Some(packet) = Packet::deserialize(&bytes.to_vec()) {
+        assert_eq!(Packet::deserialize(&packet.serialize()), Some(packet));
+    }
+});
@@ -0,0 +1,221 @@
+//! Batched datagram I/O: `recvmmsg`/`sendmmsg`, plus UDP generic segmentation offload (GSO) for sending runs of
+//! equal sized datagrams in one buffer and generic receive offload (GRO) for receiving them coalesced, where the
+//! kernel supports them. Only on Linux, `Batch::new` fails elsewhere and the driver falls back to one datagram
+//! per system call.
+
+use crate::ecn::EcnCodepoint;
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+
+/// Messages handed to the kernel by one `recvmmsg` or `sendmmsg` call.
+pub const BATCH_SIZE: usize = 16;
+
+pub struct Batch {
+    imp: imp::Batch,
+}
+
+impl Batch {
+    /// Set up batched I/O on the socket, enabling GSO and GRO if the kernel has them.
+    pub fn new(socket: &UdpSocket) -> io::Result<Batch> {
+        Ok(Batch { imp: imp::Batch::new(socket)? })
+    }
+    pub fn gso_enabled(&self) -> bool {
+        self.imp.gso
+    }
+    pub fn gro_enabled(&self) -> bool {
+        self.imp.gro
+    }
+    /// Receive up to a batch of datagrams, handing each to `on_datagram` along with its sender and ECN codepoint.
+    /// Datagrams coalesced by GRO are split back up. Returns how many datagrams were received.
+    pub fn recv<F: FnMut(&[u8], SocketAddr, EcnCodepoint)>(&mut self, socket: &UdpSocket, on_datagram: F) -> io::Result<usize> {
+        self.imp.recv(socket, on_datagram)
+    }
+    /// Send the datagrams, in order and marked with `codepoint`, to `destination`, or to the peer the socket is
+    /// connected to if None. Stops once the socket buffer is full, returns how many of the datagrams were sent.
+    pub fn send(&mut self, socket: &UdpSocket, destination: Option<SocketAddr>, codepoint: EcnCodepoint, datagrams: &[Vec<u8>]) -> io::Result<usize> {
+        self.imp.send(socket, destination, codepoint, datagrams)
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod imp {
+    use super::BATCH_SIZE;
+    use crate::ecn::{self, EcnCodepoint};
+    use std::net::{SocketAddr, UdpSocket};
+    use std::os::unix::io::AsRawFd;
+    use std::{io, mem, ptr};
+
+    // Not in every libc release, values from linux/udp.h.
+    const UDP_SEGMENT: libc::c_int = 103;
+    const UDP_GRO: libc::c_int = 104;
+    /// The kernel refuses to segment a buffer into more datagrams than this.
+    const MAX_SEGMENTS: usize = 64;
+    /// Largest buffer handed to GSO, and received through GRO.
+    const MAX_GSO_SIZE: usize = 65535;
+    /// Buffer for a single datagram when GRO is off, larger than any packet the protocol sends.
+    const DATAGRAM_SIZE: usize = 2048;
+
+    pub struct Batch {
+        pub gso: bool,
+        pub gro: bool,
+        /// The socket is IPv6, and may be dual-stack.
+        ipv6: bool,
+        receive_buffer: Vec<u8>,
+    }
+
+    impl Batch {
+        pub fn new(socket: &UdpSocket) -> io::Result<Batch> {
+            let fd = socket.as_raw_fd();
+            // GSO is set per send, the socket option only tells whether the kernel knows about it.
+            let mut segment: libc::c_int = 0;
+            let mut length = mem::size_of::<libc::c_int>() as libc::socklen_t;
+            let gso = unsafe { libc::getsockopt(fd, libc::SOL_UDP, UDP_SEGMENT, &mut segment as *mut _ as *mut libc::c_void, &mut length) } == 0;
+            let enable: libc::c_int = 1;
+            let gro = unsafe { libc::setsockopt(fd, libc::SOL_UDP, UDP_GRO, &enable as *const _ as *const libc::c_void, mem::size_of::<libc::c_int>() as libc::socklen_t) } == 0;
+            let buffer_size = if gro { MAX_GSO_SIZE } else { DATAGRAM_SIZE };
+            // Any socket that is not IPv6 only takes the IPv4 codepoint.
+            let ipv6 = socket.local_addr().map(|addr| addr.is_ipv6()).unwrap_or(false);
+            Ok(Batch { gso, gro, ipv6, receive_buffer: vec![0; BATCH_SIZE * buffer_size] })
+        }
+
+        pub fn recv<F: FnMut(&[u8], SocketAddr, EcnCodepoint)>(&mut self, socket: &UdpSocket, mut on_datagram: F) -> io::Result<usize> {
+            let buffer_size = self.receive_buffer.len() / BATCH_SIZE;
+            let mut names: [libc::sockaddr_storage; BATCH_SIZE] = unsafe { mem::zeroed() };
+            // u64 keeps the control buffers aligned for cmsghdr, room for the ECN and the GRO messages.
+            let mut controls = [[0u64; 8]; BATCH_SIZE];
+            let mut iovecs: Vec<libc::iovec> = self.receive_buffer.chunks_mut(buffer_size)
+                .map(|buf| libc::iovec { iov_base: buf.as_mut_ptr() as *mut libc::c_void, iov_len: buf.len() })
+                .collect();
+            let mut hdrs: [libc::mmsghdr; BATCH_SIZE] = unsafe { mem::zeroed() };
+            for i in 0..BATCH_SIZE {
+                hdrs[i].msg_hdr.msg_name = &mut names[i] as *mut _ as *mut libc::c_void;
+                hdrs[i].msg_hdr.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+                hdrs[i].msg_hdr.msg_iov = &mut iovecs[i];
+                hdrs[i].msg_hdr.msg_iovlen = 1;
+                hdrs[i].msg_hdr.msg_control = controls[i].as_mut_ptr() as *mut libc::c_void;
+                hdrs[i].msg_hdr.msg_controllen = mem::size_of_val(&controls[i]) as _;
+            }
+            let received = unsafe { libc::recvmmsg(socket.as_raw_fd(), hdrs.as_mut_ptr(), BATCH_SIZE as libc::c_uint, 0 as _, ptr::null_mut()) };
+            if received < 0 { return Err(io::Error::last_os_error()); }
+            let mut datagrams = 0;
+            for i in 0..received as usize {
+                let length = hdrs[i].msg_len as usize;
+                let from = ecn::to_socket_addr(&names[i])?;
+                let codepoint = ecn::read_codepoint(&hdrs[i].msg_hdr);
+                let segment = gro_segment(&hdrs[i].msg_hdr).unwrap_or(length).max(1);
+                let buf = &self.receive_buffer[i * buffer_size..i * buffer_size + length];
+                for datagram in buf.chunks(segment) {
+                    on_datagram(datagram, from, codepoint);
+                    datagrams += 1;
+                }
+            }
+            Ok(datagrams)
+        }
+
+        pub fn send(&mut self, socket: &UdpSocket, destination: Option<SocketAddr>, codepoint: EcnCodepoint, datagrams: &[Vec<u8>]) -> io::Result<usize> {
+            let mut name = destination.map(|destination| ecn::from_socket_addr(&destination));
+            let mut start = 0;
+            while start < datagrams.len() {
+                // Pack the datagrams into messages. With GSO a message carries a run of datagrams of the same
+                // size, the last one of the run may be shorter.
+                let max_segments = if self.gso { MAX_SEGMENTS } else { 1 };
+                let mut buffer = Vec::new();
+                let mut messages = Vec::new();
+                let mut next = start;
+                while next < datagrams.len() && messages.len() < BATCH_SIZE {
+                    let begin = buffer.len();
+                    let segment = datagrams[next].len();
+                    let mut count = 0;
+                    while next < datagrams.len() && count < max_segments && datagrams[next].len() <= segment && (count == 0 || buffer.len() - begin + datagrams[next].len() <= MAX_GSO_SIZE) {
+                        buffer.extend_from_slice(&datagrams[next]);
+                        next += 1;
+                        count += 1;
+                        if buffer.len() - begin < count * segment { break; }
+                    }
+                    messages.push((begin..buffer.len(), segment as u16, count));
+                }
+
+                let mut iovecs: Vec<libc::iovec> = messages.iter()
+                    .map(|(range, _, _)| libc::iovec { iov_base: buffer[range.clone()].as_ptr() as *mut libc::c_void, iov_len: range.len() })
+                    .collect();
+                // Room for the GSO segment size and the ECN codepoint of both IPv4 and IPv6.
+                let mut controls = vec![[0u64; 12]; messages.len()];
+                let mut hdrs: Vec<libc::mmsghdr> = vec![unsafe { mem::zeroed() }; messages.len()];
+                for (i, (_, segment, count)) in messages.iter().enumerate() {
+                    hdrs[i].msg_hdr.msg_iov = &mut iovecs[i];
+                    hdrs[i].msg_hdr.msg_iovlen = 1;
+                    if let Some((name, length)) = &mut name {
+                        hdrs[i].msg_hdr.msg_name = name as *mut _ as *mut libc::c_void;
+                        hdrs[i].msg_hdr.msg_namelen = *length;
+                    }
+                    let mut control_length = ecn::push_codepoint(&mut controls[i], 0, self.ipv6, codepoint);
+                    if *count > 1 {
+                        control_length = ecn::push_cmsg(&mut controls[i], control_length, libc::SOL_UDP, UDP_SEGMENT, &segment.to_ne_bytes());
+                    }
+                    if control_length > 0 {
+                        hdrs[i].msg_hdr.msg_control = controls[i].as_mut_ptr() as *mut libc::c_void;
+                        hdrs[i].msg_hdr.msg_controllen = control_length as _;
+                    }
+                }
+                let sent = unsafe { libc::sendmmsg(socket.as_raw_fd(), hdrs.as_mut_ptr(), hdrs.len() as libc::c_uint, 0 as _) };
+                if sent < 0 {
+                    let error = io::Error::last_os_error();
+                    match error.raw_os_error() {
+                        Some(libc::EWOULDBLOCK) => {
+                            debug!("Socket buffer full, {} datagrams not sent.", datagrams.len() - start);
+                            return Ok(start);
+                        }
+                        // The device cannot segment, send them one by one from now on.
+                        Some(libc::EIO) | Some(libc::EINVAL) if self.gso && messages.iter().any(|(_, _, count)| *count > 1) => {
+                            info!("GSO failed, disabling it: {}", error);
+                            self.gso = false;
+                            continue;
+                        }
+                        _ => return Err(error),
+                    }
+                }
+                start += messages[..sent as usize].iter().map(|(_, _, count)| count).sum::<usize>();
+            }
+            Ok(start)
+        }
+    }
+
+    /// The size of the datagrams GRO coalesced into this message, if it did.
+    fn gro_segment(hdr: &libc::msghdr) -> Option<usize> {
+        let mut cmsg = unsafe { libc::CMSG_FIRSTHDR(hdr) };
+        while !cmsg.is_null() {
+            let (level, kind) = unsafe { ((*cmsg).cmsg_level, (*cmsg).cmsg_type) };
+            if level == libc::SOL_UDP && kind == UDP_GRO {
+                return Some(unsafe { ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const libc::c_int) } as usize);
+            }
+            cmsg = unsafe { libc::CMSG_NXTHDR(hdr, cmsg) };
+        }
+        None
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod imp {
+    use crate::ecn::EcnCodepoint;
+    use std::io;
+    use std::net::{SocketAddr, UdpSocket};
+
+    pub struct Batch {
+        pub gso: bool,
+        pub gro: bool,
+    }
+
+    impl Batch {
+        pub fn new(_socket: &UdpSocket) -> io::Result<Batch> {
+            Err(io::Error::new(io::ErrorKind::Other, "batched I/O is only supported on Linux"))
+        }
+
+        pub fn recv<F: FnMut(&[u8], SocketAddr, EcnCodepoint)>(&mut self, _socket: &UdpSocket, _on_datagram: F) -> io::Result<usize> {
+            unreachable!()
+        }
+
+        pub fn send(&mut self, _socket: &UdpSocket, _destination: Option<SocketAddr>, _codepoint: EcnCodepoint, _datagrams: &[Vec<u8>]) -> io::Result<usize> {
+            unreachable!()
+        }
+    }
+}
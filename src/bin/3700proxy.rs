@@ -0,0 +1,140 @@
+use chrono::prelude::*;
+use clap::{App, Arg};
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
+
+extern crate pretty_env_logger;
+#[macro_use]
+extern crate log;
+
+use PROJECT3::event_loop::EventLoop;
+use PROJECT3::sim::{Link, LinkConfig};
+use PROJECT3::udp;
+
+fn main() {
+    pretty_env_logger::init();
+    debug!("Proxy Started");
+    let args = App::new("CS3700 Project 3")
+        .author("Nelson Chan <chan.chak@husky.neu.edu>")
+        .about("Forwards datagrams between 3700send and 3700recv, impairing them like the course netsim")
+        .arg(Arg::with_name("receiver").index(1).required(true).help("The receiver to forward to, e.g. 127.0.0.1:28899"))
+        .arg(Arg::with_name("port").short("p").long("port").takes_value(true).default_value("28900").help("Port to accept the sender on"))
+        .arg(Arg::with_name("bandwidth").long("bandwidth").takes_value(true).default_value("1").validator(positive).help("Link rate in Mb/s"))
+        .arg(Arg::with_name("latency").long("latency").takes_value(true).default_value("10").validator(non_negative).help("One-way latency in ms"))
+        .arg(Arg::with_name("jitter").long("jitter").takes_value(true).default_value("0").validator(non_negative).help("Latency varies by up to this many ms either way"))
+        .arg(Arg::with_name("drop").long("drop").takes_value(true).default_value("0").validator(percentage).help("Percentage of datagrams dropped"))
+        .arg(Arg::with_name("duplicate").long("duplicate").takes_value(true).default_value("0").validator(percentage).help("Percentage of datagrams duplicated"))
+        .arg(Arg::with_name("reorder").long("reorder").takes_value(true).default_value("0").validator(percentage).help("Percentage of datagrams reordered"))
+        .arg(Arg::with_name("delay").long("delay").takes_value(true).default_value("0").validator(percentage).help("Percentage of datagrams delayed by an extra 100 ms"))
+        .arg(Arg::with_name("burst").long("burst").takes_value(true).default_value("0").validator(percentage).help("Percentage chance of a loss burst starting"))
+        .arg(Arg::with_name("burst length").long("burst-length").takes_value(true).default_value("3").validator(count).help("Datagrams dropped in a row by a loss burst"))
+        .arg(Arg::with_name("seed").long("seed").takes_value(true).validator(seed).help("Seed of the random decisions, random if not given"))
+        .get_matches();
+    let receiver: SocketAddr = args.value_of("receiver").unwrap().parse().expect("Receiver address incorrect formatting");
+    // The validators already checked every number.
+    let number = |name: &str| -> f64 { args.value_of(name).unwrap().parse().unwrap() };
+    let link = LinkConfig {
+        bandwidth: number("bandwidth"),
+        latency: Duration::from_micros((number("latency") * 1000.0) as u64),
+        jitter: Duration::from_micros((number("jitter") * 1000.0) as u64),
+        drop: number("drop"),
+        duplicate: number("duplicate"),
+        reorder: number("reorder"),
+        delay: number("delay"),
+        burst: number("burst"),
+        burst_length: number("burst length") as u32,
+        ..LinkConfig::default()
+    };
+    let seed = match args.value_of("seed") {
+        Some(seed) => seed.parse().unwrap(),
+        None => rand::random(),
+    };
+    eprintln!("{:?} [seed] {}", Local::now(), seed);
+    let mut rng = StdRng::seed_from_u64(seed);
+
+    let socket = udp::bind_socket_with_port("127.0.0.1", args.value_of("port").unwrap()).expect("Failed to bind");
+    let mut event_loop = EventLoop::new(&socket).expect("Failed to create event loop");
+    let now = Instant::now();
+    let mut to_receiver = Link::new(link.clone(), now);
+    let mut to_sender = Link::new(link, now);
+    let mut sender: Option<SocketAddr> = None;
+
+    let mut buf = [0; 2000];
+    loop {
+        loop {
+            let (num_bytes_read, from) = match socket.recv_from(&mut buf) {
+                Ok(result) => result,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                // Such as an ICMP port unreachable for an earlier datagram, while the receiver is not up yet.
+                Err(e) => { eprintln!("{:?} [error] {}", Local::now(), e); continue; }
+            };
+            let now = Instant::now();
+            let (direction, link) = if from == receiver {
+                ("recv -> send", &mut to_sender)
+            } else {
+                if sender != Some(from) { eprintln!("{:?} [sender] {}", Local::now(), from); }
+                sender = Some(from);
+                ("send -> recv", &mut to_receiver)
+            };
+            let arrivals = link.send(buf[..num_bytes_read].to_vec(), now, &mut rng);
+            if arrivals.is_empty() {
+                eprintln!("{:?} [dropped] {} ({})", Local::now(), direction, num_bytes_read);
+            } else {
+                let delays = arrivals.iter().map(|arrival| format!("{:.1}ms", (*arrival - now).as_secs_f64() * 1000.0)).collect::<Vec<String>>();
+                eprintln!("{:?} [queued] {} ({}) {}", Local::now(), direction, num_bytes_read, delays.join(" "));
+            }
+        }
+        let now = Instant::now();
+        for datagram in to_receiver.deliver(now) {
+            forward(&socket, &datagram, receiver, "send -> recv");
+        }
+        for datagram in to_sender.deliver(now) {
+            match sender {
+                Some(sender) => forward(&socket, &datagram, sender, "recv -> send"),
+                None => eprintln!("{:?} [dropped] recv -> send ({}), no sender yet", Local::now(), datagram.len()),
+            }
+        }
+        let deadline = [to_receiver.next_arrival(), to_sender.next_arrival()].iter().filter_map(|arrival| *arrival).min();
+        event_loop.wait(deadline).expect("Event loop failed");
+    }
+}
+
+/// Send a datagram on. A failed send only loses this datagram, as the network would.
+fn forward(socket: &UdpSocket, datagram: &[u8], to: SocketAddr, direction: &str) {
+    match socket.send_to(datagram, to) {
+        Ok(_) => eprintln!("{:?} [forwarded] {} ({})", Local::now(), direction, datagram.len()),
+        Err(e) => eprintln!("{:?} [dropped] {} ({}), {}", Local::now(), direction, datagram.len(), e),
+    }
+}
+
+fn percentage(value: String) -> Result<(), String> {
+    match value.parse::<f64>() {
+        Ok(percent) if (0.0..=100.0).contains(&percent) => Ok(()),
+        _ => Err(format!("{} is not a percentage from 0 to 100", value)),
+    }
+}
+
+fn positive(value: String) -> Result<(), String> {
+    match value.parse::<f64>() {
+        Ok(number) if number > 0.0 && number.is_finite() => Ok(()),
+        _ => Err(format!("{} is not a number above 0", value)),
+    }
+}
+
+fn non_negative(value: String) -> Result<(), String> {
+    match value.parse::<f64>() {
+        Ok(number) if number >= 0.0 && number.is_finite() => Ok(()),
+        _ => Err(format!("{} is not a number of 0 or more", value)),
+    }
+}
+
+fn count(value: String) -> Result<(), String> {
+    value.parse::<u32>().map(|_| ()).map_err(|_| format!("{} is not a whole number of 0 or more", value))
+}
+
+fn seed(value: String) -> Result<(), String> {
+    value.parse::<u64>().map(|_| ()).map_err(|_| format!("{} is not a whole number of 0 or more", value))
+}
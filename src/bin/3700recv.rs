@@ -1,103 +1,101 @@
 use chrono::prelude::*;
-use clap::{App, Arg};
-use rand::Rng;
-use std::collections::{HashMap, VecDeque};
-use std::io::{self, Read, Write};
-use std::{str, cmp};
-use std::time::{Duration, Instant};
+#[macro_use]
+extern crate clap;
+use clap::{App, Arg, ArgMatches};
+use std::io::{self, Write};
+use std::fs::File;
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::process;
+use std::thread;
 
 extern crate pretty_env_logger;
 #[macro_use]
 extern crate log;
 
-use PROJECT3::protocol;
+use PROJECT3::config::{self, ConnectionConfig};
+use PROJECT3::connection::Connection;
+use PROJECT3::error::Error;
+use PROJECT3::listener::Listener;
 
 fn main() {
     pretty_env_logger::init();
     debug!("Receiver Started");
-    // let socket = protocol::bind_socket("127.0.0.1");
-    let socket = protocol::bind_socket_with_port("127.0.0.1", "28899");
-
-    let mut rng = rand::thread_rng();
-    let initial_packet_num = rng.gen_range(1, u8::max_value() as u64);
-    let mut state = protocol::State {
-        connected: false,
-        closing: None,
-        established: false,
-        initial_sent_packet_num: initial_packet_num,
-        last_packet_num: initial_packet_num - 1,
-        initial_received_packet_num: 0,
-        time_of_last_received_packet: None,
-        time_of_last_sent_new_packet: None,
-        connection_creation_time: Some(Instant::now()),
-        sent_largest_ACKed: 0,
-        sent_largest_lost: 0,
-        sent_packets: HashMap::new(),
-        sent_ack_largest: HashMap::new(),
-        sent_end_byte_processed: false,
-
-        received_largest: 0,
-        received_packets: HashMap::new(),
-        time_of_last_packet_reorder: None,
-        next_byte_offset: 0,
-        lost_packets: VecDeque::new(),
-        ack_starting_packet_num: 0,
-
-        socket: socket,
-
-        PTO_amount: 0,
-        last_PTO: 0,
-        last_PTO_time: None,
-        max_RTT: 0,
-        min_RTT: 0,
-        latest_RTT: 0,
-        smoothed_RTT: 0,
-        RTT_variance: 0,
-        congestion_window: 14720,
-        max_congestion_window: 14720,
-        bytes_in_flight: 0,
-        slow_start_threshold: usize::max_value(),
-        congestion_recovery_start_time: None,
-        packet_sent: 0,
-        packet_lost: 0,
-
-        send_state: protocol::StreamSendState {
-            sent_data: HashMap::new(),
-            send_queue: VecDeque::new(),
-        },
-        receive_state: protocol::StreamReceiveState {
-            received_data: HashMap::new(),
-            assembled_data: Vec::new(),
-            end_received: None,
-        },
-    };
-
-    while state.established == false {
-        let received = state.receive_packet();
-        if !received && state.should_send_ACK() { state.send_ACK(); }
-        state.detect_packet_lost();
+    let args = App::new("CS3700 Project 3")
+        .author("Nelson Chan <chan.chak@husky.neu.edu>")
+        .arg(
+            Arg::with_name("bind")
+            .long("bind")
+            .takes_value(true)
+            .default_value("127.0.0.1")
+            .help("The address to listen on, :: for every IPv6 and IPv4 address"),
+        )
+        .arg(
+            Arg::with_name("port")
+            .long("port")
+            .takes_value(true)
+            .default_value("28899")
+            .help("The port to listen on, 0 picks a free one"),
+        )
+        .arg(
+            Arg::with_name("output dir")
+            .short("o")
+            .long("output-dir")
+            .takes_value(true)
+            .help("Serve any number of senders at once until killed, each upload written to a file of its own here"),
+        )
+        .args(&config::args())
+        .get_matches();
+    if let Err(error) = run(&args) {
+        eprintln!("{:?} [error] {}", Local::now(), error);
+        process::exit(1);
     }
+}
 
-    let mut more_to_receive = true;
-    while more_to_receive {
-        let received = state.receive_packet();
-        if !received && state.should_send_ACK() { state.send_ACK(); }
-        state.detect_packet_lost();
-        if !received && state.receive_state.end_received != None && state.assemble_remaining_data() { more_to_receive = false; }
+fn run(args: &ArgMatches) -> Result<(), Error> {
+    let bind = value_t!(args, "bind", IpAddr).unwrap_or_else(|error| error.exit());
+    let port = value_t!(args, "port", u16).unwrap_or_else(|error| error.exit());
+    let config = ConnectionConfig::from_args(args)?;
+    let mut listener = Listener::bind(SocketAddr::new(bind, port), config)?;
+    // For scripts, in a fixed format without a timestamp: the address actually bound, with the port picked if
+    // 0 was asked for.
+    eprintln!("listening {}", listener.local_addr()?);
+    if let Some(dir) = args.value_of("output dir") {
+        return serve(listener, Path::new(dir));
     }
+    let mut connection = listener.accept()?;
+
+    // Data is written out as soon as it is contiguous, so the file is never held in memory as a whole.
+    let stdout = io::stdout();
+    let mut output = stdout.lock();
+    io::copy(&mut connection, &mut output)?;
+    output.flush()?;
     eprintln!("{:?} [completed]", Local::now());
 
-    io::stdout().write_all(&state.receive_state.assembled_data).unwrap();
+    connection.close()
+}
 
-    let mut timer = Instant::now();
-    let mut close_attempt = 1;
-    state.send_close_packet();
-    while state.connected && close_attempt < 3 {
-        while {state.receive_packet()} {}
-        if timer.elapsed() > cmp::max(2 * Duration::from_nanos(state.smoothed_RTT), Duration::from_millis(100)) {
-            state.send_close_packet();
-            close_attempt += 1;
-            timer = Instant::now();
-        }
+/// Accept senders until killed, each read by a thread of its own into a file named after the order it came in
+/// and its address.
+fn serve(mut listener: Listener, dir: &Path) -> Result<(), Error> {
+    for number in 1.. {
+        let connection = listener.accept()?;
+        let peer = connection.peer_addr();
+        let name: String = format!("{}-{}", number, peer).chars().map(|c| if c.is_ascii_alphanumeric() || c == '.' || c == '-' { c } else { '_' }).collect();
+        let path = dir.join(name);
+        eprintln!("{:?} [accepted] {} -> {}", Local::now(), peer, path.display());
+        thread::spawn(move || match receive(connection, &path) {
+            Ok(bytes) => eprintln!("{:?} [completed] {} ({})", Local::now(), peer, bytes),
+            Err(error) => eprintln!("{:?} [error] {}: {}", Local::now(), peer, error),
+        });
     }
+    Ok(())
+}
+
+fn receive(mut connection: Connection, path: &Path) -> Result<u64, Error> {
+    let mut output = io::BufWriter::new(File::create(path)?);
+    let bytes = io::copy(&mut connection, &mut output)?;
+    output.flush()?;
+    connection.close()?;
+    Ok(bytes)
 }
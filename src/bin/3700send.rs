@@ -1,22 +1,150 @@
 use std::io;
 use std::io::prelude::*;
-use std::collections::{HashMap, VecDeque};
 use std::cmp;
 use chrono::prelude::*;
 use rand::Rng;
+#[macro_use]
 extern crate clap;
-use clap::{Arg, App};
-use std::time::{Duration, Instant};
+use clap::{Arg, App, ArgMatches};
+#[cfg(unix)]
+use std::os::unix::io::AsRawFd;
+use std::process;
 
 extern crate pretty_env_logger;
 #[macro_use]
 extern crate log;
 
-use PROJECT3::protocol;
+use PROJECT3::config::{self, ConnectionConfig};
+use PROJECT3::connection::Connection;
+use PROJECT3::error::Error;
+use PROJECT3::event_loop::RawFd;
+
+/// Size of one read from the input.
+const READ_SIZE: usize = 64 * 1024;
+
+/// Where the bytes to send come from. Read only as fast as the connection takes them, so the input is
+/// streamed and never held in memory as a whole.
+enum Input {
+    Random { remaining: u64 },
+    Stdin(Stdin),
+}
+
+/// Stdin, read without blocking where the platform allows it, so a slow producer does not hold up ACKs and
+/// retransmissions. Elsewhere reads block, between the short sleeps of the event loop.
+struct Stdin {
+    #[cfg(unix)]
+    fd: RawFd,
+    /// The flags stdin had, restored on drop: stdin may be shared with the shell, and has to be left as we
+    /// found it however the sender exits.
+    #[cfg(unix)]
+    flags: libc::c_int,
+}
+
+impl Stdin {
+    #[cfg(unix)]
+    fn new() -> io::Result<Stdin> {
+        let fd = io::stdin().as_raw_fd();
+        let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+        if flags < 0 || unsafe { libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Stdin { fd, flags })
+    }
+    #[cfg(not(unix))]
+    fn new() -> io::Result<Stdin> {
+        Ok(Stdin {})
+    }
+    /// The descriptor the event loop can wait on.
+    #[cfg(unix)]
+    fn fd(&self) -> Option<RawFd> {
+        Some(self.fd)
+    }
+    #[cfg(not(unix))]
+    fn fd(&self) -> Option<RawFd> {
+        None
+    }
+}
+
+#[cfg(unix)]
+impl Drop for Stdin {
+    fn drop(&mut self) {
+        unsafe { libc::fcntl(self.fd, libc::F_SETFL, self.flags); }
+    }
+}
+
+/// The input and what was read of it but not yet taken by the connection.
+struct InputReader {
+    input: Input,
+    unsent: Vec<u8>,
+    /// Offset of the first byte of `unsent` the connection has not taken.
+    unsent_from: usize,
+    finished: bool,
+}
+
+impl Input {
+    fn random(length: u64) -> Input {
+        Input::Random { remaining: length }
+    }
+    fn stdin() -> io::Result<Input> {
+        Ok(Input::Stdin(Stdin::new()?))
+    }
+    /// Read the next chunk into `chunk`, 0 at the end of the input.
+    fn read(&mut self, chunk: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Input::Random { remaining } => {
+                let length = cmp::min(chunk.len() as u64, *remaining) as usize;
+                rand::thread_rng().fill(&mut chunk[..length]);
+                *remaining -= length as u64;
+                Ok(length)
+            }
+            Input::Stdin(_) => io::stdin().lock().read(chunk),
+        }
+    }
+}
+
+impl InputReader {
+    fn new(input: Input) -> InputReader {
+        InputReader { input, unsent: Vec::new(), unsent_from: 0, finished: false }
+    }
+    /// The input to wait on, and whether to wait on it: only once the connection took everything read so far.
+    fn wants_to_read(&self) -> Option<(RawFd, bool)> {
+        match self.input {
+            Input::Stdin(ref stdin) => stdin.fd().map(|fd| (fd, !self.finished && self.unsent_from == self.unsent.len())),
+            Input::Random { .. } => None,
+        }
+    }
+    /// Write as much input into the connection as is available and it takes, ending the stream at the end.
+    fn fill(&mut self, connection: &mut Connection) -> Result<(), Error> {
+        while !self.finished {
+            if self.unsent_from == self.unsent.len() {
+                self.unsent_from = 0;
+                self.unsent.resize(READ_SIZE, 0);
+                let read = match self.input.read(&mut self.unsent) {
+                    Ok(read) => read,
+                    Err(ref error) if error.kind() == io::ErrorKind::WouldBlock => { self.unsent.clear(); return Ok(()); }
+                    Err(ref error) if error.kind() == io::ErrorKind::Interrupted => { self.unsent.clear(); continue; }
+                    Err(error) => return Err(error.into()),
+                };
+                self.unsent.truncate(read);
+                if read == 0 {
+                    debug!("Reached the end of the input.");
+                    connection.shutdown()?;
+                    self.finished = true;
+                    return Ok(());
+                }
+            }
+            match connection.write(&self.unsent[self.unsent_from..]) {
+                Ok(written) => self.unsent_from += written,
+                Err(ref error) if error.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(error) => return Err(error.into()),
+            }
+        }
+        Ok(())
+    }
+}
 
 fn main() {
     pretty_env_logger::init();
-    let mut rng = rand::thread_rng();
     debug!("Sender Started");
     let args = App::new("CS3700 Project 3")
         .author("Nelson Chan <chan.chak@husky.neu.edu>")
@@ -34,110 +162,36 @@ fn main() {
             .takes_value(true)
             .help("Generate random bytes as input"),
         )
+        .args(&config::args())
         .get_matches();
-    let client = args.value_of("client").unwrap();
-    let random = args.value_of("random bytes").unwrap_or("none");
-    let client_ip = &client[0..client.find(":").expect("Argument Incorrect formatting")];
-    let client_port = &client[client.find(":").unwrap() + 1..];
-
-
-    let socket = protocol::bind_socket(client_ip);
-    socket.connect(format!("127.0.0.1:{}", client_port)).expect("connect to receiver failed");
-    debug!("Receiver connected");
-
-    let initial_packet_num = rng.gen_range(1, u8::max_value() as u64);
-    let mut state = protocol::State {
-        connected: true,
-        closing: None,
-        established: false,
-        initial_sent_packet_num: initial_packet_num,
-        last_packet_num: initial_packet_num - 1,
-        initial_received_packet_num: 0,
-        time_of_last_received_packet: None,
-        time_of_last_sent_new_packet: None,
-        connection_creation_time: Some(Instant::now()),
-        sent_largest_ACKed: 0,
-        sent_largest_lost: 0,
-        sent_packets: HashMap::new(),
-        sent_ack_largest: HashMap::new(),
-        sent_end_byte_processed: false,
-
-        received_largest: 0,
-        received_packets: HashMap::new(),
-        time_of_last_packet_reorder: None,
-        next_byte_offset: 0,
-        lost_packets: VecDeque::new(),
-        ack_starting_packet_num: 0,
-
-        socket: socket,
-
-        PTO_amount: 0,
-        last_PTO: 0,
-        last_PTO_time: None,
-        max_RTT: 0,
-        min_RTT: 0,
-        latest_RTT: 0,
-        smoothed_RTT: 0,
-        RTT_variance: 0,
-        congestion_window: 14720,
-        max_congestion_window: 14720,
-        bytes_in_flight: 0,
-        slow_start_threshold: usize::max_value(),
-        congestion_recovery_start_time: None,
-        packet_sent: 0,
-        packet_lost: 0,
-
-        send_state: protocol::StreamSendState {
-            sent_data: HashMap::new(),
-            send_queue: VecDeque::new(),
-        },
-        receive_state: protocol::StreamReceiveState {
-            received_data: HashMap::new(),
-            assembled_data: Vec::new(),
-            end_received: None,
-        },
-    };
-
-    let mut buffer = Vec::new();
-    if random != "none" {
-        let length:u32 = random.parse().unwrap();
-        buffer = (0..length).map(|_| { rand::random::<u8>() }).collect();
-    } else {
-        io::stdin().read_to_end(&mut buffer).expect("Error on reading input");
+    if let Err(error) = run(&args) {
+        eprintln!("{:?} [error] {}", Local::now(), error);
+        process::exit(1);
     }
+}
 
-    state.build_new_data_packet(&buffer);
-    state.send_a_packet_in_queue();
-    while state.established == false {
-        state.receive_packet();
-//        if !received && state.should_send_ACK() { state.send_ACK(); }
-        state.detect_packet_lost();
-        state.resend_lost_packet_data(&buffer);
-    }
+fn run(args: &ArgMatches) -> Result<(), Error> {
+    let client = args.value_of("client").unwrap();
+    let random = if args.is_present("random bytes") { Some(value_t!(args, "random bytes", u64).unwrap_or_else(|error| error.exit())) } else { None };
+    let config = ConnectionConfig::from_args(args)?;
 
-    while state.closing == None {
-        if state.bytes_in_flight <= state.congestion_window {
-            state.resend_lost_packet_data(&buffer);
-        }
-        if state.bytes_in_flight <= 3000 {
-            state.send_all_in_queue();
-            state.send_new_data(&buffer);
-        }
-        let received = state.receive_packet();
-        if !received && state.should_send_ACK() { state.send_ACK(); }
-        state.detect_packet_lost();
-        // if state.sent_end_byte_processed && state.lost_packets.len() == 0 && state.send_state.send_queue.len() == 0 && state.bytes_in_flight == 0 { more_to_send = false; }
-    }
-    eprintln!("{:?} [completed]", Local::now());
+    let mut connection = Connection::connect(client, config)?;
+    let mut input = InputReader::new(match random {
+        Some(length) => Input::random(length),
+        None => Input::stdin()?,
+    });
 
-    let mut timer = Instant::now();
-    let mut close_attempt = 0;
-    while state.connected && close_attempt < 3 {
-        while {state.receive_packet()} {}
-        if timer.elapsed() > cmp::max(2 * Duration::from_nanos(state.smoothed_RTT), Duration::from_millis(100)) {
-            state.send_close_packet();
-            close_attempt += 1;
-            timer = Instant::now();
+    // Feed the input to the connection as it takes it, waiting on whichever of the two is ready first.
+    connection.set_nonblocking(true);
+    while !input.finished {
+        input.fill(&mut connection)?;
+        if let Some((fd, interested)) = input.wants_to_read() {
+            connection.watch_input(fd, interested)?;
         }
+        if !input.finished { connection.wait()?; }
     }
-}
\ No newline at end of file
+    connection.set_nonblocking(false);
+    connection.flush()?;
+    eprintln!("{:?} [completed]", Local::now());
+    connection.close()
+}
@@ -0,0 +1,53 @@
+//! Source of time for the protocol. The real clock reads `Instant::now()`, the mock clock only moves
+//! when told to, so timers can be tested deterministically and simulations can skip ahead in time.
+
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+pub trait Clock: fmt::Debug + Send + Sync {
+    fn now(&self) -> Instant;
+}
+
+/// Wall clock, what the binaries run on.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RealClock;
+
+impl Clock for RealClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// Manually advanced clock. Clones share the same time, so a test can keep one handle and give another to
+/// the `State` under test.
+#[derive(Clone, Debug)]
+pub struct MockClock {
+    now: Arc<Mutex<Instant>>,
+}
+
+impl MockClock {
+    pub fn new() -> MockClock {
+        MockClock { now: Arc::new(Mutex::new(Instant::now())) }
+    }
+    pub fn advance(&self, duration: Duration) {
+        *self.now.lock().unwrap() += duration;
+    }
+    /// Jump to `instant`. Time never goes backwards, earlier instants are ignored.
+    pub fn advance_to(&self, instant: Instant) {
+        let mut now = self.now.lock().unwrap();
+        if instant > *now { *now = instant; }
+    }
+}
+
+impl Default for MockClock {
+    fn default() -> MockClock {
+        MockClock::new()
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        *self.now.lock().unwrap()
+    }
+}
@@ -0,0 +1,267 @@
+//! Settings a connection is created with. Built from the defaults with `ConnectionConfig::builder`, read from a
+//! TOML file, or taken from the command line of the binaries, which may name a file and override any setting.
+
+#![allow(non_snake_case)]
+
+use crate::send_buffer;
+use clap::{Arg, ArgMatches};
+use serde::Deserialize;
+use std::{error, fmt, fs, io};
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Smallest datagram that leaves room for the packet header, a DATA frame header and some data.
+pub const MIN_DATAGRAM_SIZE: usize = 256;
+/// Largest UDP payload in a 1500 byte Ethernet frame over IPv4, larger datagrams would be fragmented.
+pub const MAX_DATAGRAM_SIZE: usize = 1472;
+
+#[derive(PartialEq, Clone, Debug)]
+pub struct ConnectionConfig {
+    /// Bytes written but not yet acknowledged that the sender holds on to. Writes wait while it is full. The
+    /// receiver takes data up to as far past a gap in the stream, so both ends need the same value.
+    pub send_buffer_capacity: usize,
+    /// Largest datagram sent, and the unit the congestion window grows by.
+    pub max_datagram_size: usize,
+    /// Congestion window at the start, and after an idle period or repeated PTOs.
+    pub initial_window: usize,
+    /// The congestion window is never reduced below this.
+    pub minimum_window: usize,
+    /// How long the receiver may hold back an ACK.
+    pub max_ack_delay: Duration,
+    /// Largest ACK delay reported by the peer that is taken off RTT samples.
+    pub peer_max_ack_delay: Duration,
+    /// PTO before the first RTT sample.
+    pub initial_PTO: Duration,
+    /// The PTO stops doubling at this.
+    pub max_PTO: Duration,
+    /// The congestion window is multiplied by this on a congestion event while sending faster than the
+    /// estimated bandwidth.
+    pub backoff_above_bandwidth: f64,
+    /// Same, while sending slower than the estimated bandwidth.
+    pub backoff_below_bandwidth: f64,
+}
+
+impl Default for ConnectionConfig {
+    fn default() -> ConnectionConfig {
+        ConnectionConfig {
+            send_buffer_capacity: send_buffer::DEFAULT_CAPACITY,
+            max_datagram_size: 1472,
+            initial_window: 14720,
+            minimum_window: 14720,
+            max_ack_delay: Duration::from_millis(5),
+            peer_max_ack_delay: Duration::from_millis(2),
+            initial_PTO: Duration::from_millis(500),
+            max_PTO: Duration::from_millis(1200),
+            backoff_above_bandwidth: 0.6,
+            backoff_below_bandwidth: 0.8,
+        }
+    }
+}
+
+impl ConnectionConfig {
+    /// Start from the defaults, change what is needed and `build`.
+    pub fn builder() -> ConnectionConfigBuilder {
+        ConnectionConfigBuilder { config: ConnectionConfig::default() }
+    }
+    /// Read the settings from a TOML file, see `from_toml`.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<ConnectionConfig, ConfigError> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path).map_err(|error| ConfigError::Io(path.display().to_string(), error))?;
+        ConnectionConfig::from_toml(&text)
+    }
+    /// Read the settings from TOML, with the names of the command line options: `initial_window = 29440`,
+    /// `max_pto_ms = 2000`. Settings left out keep their default.
+    pub fn from_toml(text: &str) -> Result<ConnectionConfig, ConfigError> {
+        let settings: Settings = toml::from_str(text).map_err(|error| ConfigError::Parse(error.to_string()))?;
+        settings.apply(ConnectionConfig::builder()).build()
+    }
+    /// The settings from the file given with `--config` if any, with the other options of `args` on top.
+    pub fn from_args(args: &ArgMatches) -> Result<ConnectionConfig, ConfigError> {
+        let builder = match args.value_of("config") {
+            Some(path) => ConnectionConfig::load(path)?.into_builder(),
+            None => ConnectionConfig::builder(),
+        };
+        Settings::from_args(args)?.apply(builder).build()
+    }
+    /// Check that the settings make sense together.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |reason: String| Err(ConfigError::Invalid(reason));
+        if self.max_datagram_size < MIN_DATAGRAM_SIZE || self.max_datagram_size > MAX_DATAGRAM_SIZE {
+            return invalid(format!("max_datagram_size must be between {} and {}", MIN_DATAGRAM_SIZE, MAX_DATAGRAM_SIZE));
+        }
+        if self.minimum_window < 2 * self.max_datagram_size {
+            return invalid("minimum_window must hold at least two datagrams".to_string());
+        }
+        if self.initial_window < self.minimum_window {
+            return invalid("initial_window must not be below minimum_window".to_string());
+        }
+        if self.send_buffer_capacity == 0 {
+            return invalid("send_buffer_capacity must not be 0".to_string());
+        }
+        if self.initial_PTO == Duration::from_millis(0) || self.max_PTO < self.initial_PTO {
+            return invalid("initial_pto must be above 0 and not above max_pto".to_string());
+        }
+        for (name, factor) in &[("backoff_above_bandwidth", self.backoff_above_bandwidth), ("backoff_below_bandwidth", self.backoff_below_bandwidth)] {
+            if !(*factor > 0.0 && *factor < 1.0) {
+                return invalid(format!("{} must be between 0 and 1", name));
+            }
+        }
+        Ok(())
+    }
+    fn into_builder(self) -> ConnectionConfigBuilder {
+        ConnectionConfigBuilder { config: self }
+    }
+}
+
+/// Changes settings one at a time, checked together by `build`.
+#[derive(Clone, Debug)]
+pub struct ConnectionConfigBuilder {
+    config: ConnectionConfig,
+}
+
+impl ConnectionConfigBuilder {
+    pub fn send_buffer_capacity(mut self, bytes: usize) -> Self {
+        self.config.send_buffer_capacity = bytes;
+        self
+    }
+    pub fn max_datagram_size(mut self, bytes: usize) -> Self {
+        self.config.max_datagram_size = bytes;
+        self
+    }
+    pub fn initial_window(mut self, bytes: usize) -> Self {
+        self.config.initial_window = bytes;
+        self
+    }
+    pub fn minimum_window(mut self, bytes: usize) -> Self {
+        self.config.minimum_window = bytes;
+        self
+    }
+    pub fn max_ack_delay(mut self, delay: Duration) -> Self {
+        self.config.max_ack_delay = delay;
+        self
+    }
+    pub fn peer_max_ack_delay(mut self, delay: Duration) -> Self {
+        self.config.peer_max_ack_delay = delay;
+        self
+    }
+    pub fn initial_PTO(mut self, PTO: Duration) -> Self {
+        self.config.initial_PTO = PTO;
+        self
+    }
+    pub fn max_PTO(mut self, PTO: Duration) -> Self {
+        self.config.max_PTO = PTO;
+        self
+    }
+    pub fn backoff_above_bandwidth(mut self, factor: f64) -> Self {
+        self.config.backoff_above_bandwidth = factor;
+        self
+    }
+    pub fn backoff_below_bandwidth(mut self, factor: f64) -> Self {
+        self.config.backoff_below_bandwidth = factor;
+        self
+    }
+    pub fn build(self) -> Result<ConnectionConfig, ConfigError> {
+        self.config.validate()?;
+        Ok(self.config)
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    /// The settings do not make sense, and why.
+    Invalid(String),
+    /// The file could not be read.
+    Io(String, io::Error),
+    /// The file is not TOML, has a setting that does not exist, or a value that is not a number.
+    Parse(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::Invalid(reason) => write!(f, "invalid connection settings: {}", reason),
+            ConfigError::Io(path, error) => write!(f, "cannot read {}: {}", path, error),
+            ConfigError::Parse(reason) => write!(f, "cannot parse connection settings: {}", reason),
+        }
+    }
+}
+
+impl error::Error for ConfigError {}
+
+/// The command line options that make up a config, for both binaries.
+pub fn args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    let mut args = vec![Arg::with_name("config").long("config").takes_value(true).value_name("FILE").help("Read the connection settings from a TOML file")];
+    for &(name, help) in OPTIONS {
+        args.push(Arg::with_name(name).long(name).takes_value(true).help(help));
+    }
+    args
+}
+
+/// Options on the command line, named like the keys of the TOML file with dashes.
+const OPTIONS: &[(&str, &str)] = &[
+    ("send-buffer-capacity", "Bytes written but not yet acknowledged that the sender holds on to"),
+    ("max-datagram-size", "Largest datagram sent, in bytes"),
+    ("initial-window", "Congestion window at the start, in bytes"),
+    ("minimum-window", "Smallest congestion window, in bytes"),
+    ("max-ack-delay-ms", "How long the receiver may hold back an ACK"),
+    ("peer-max-ack-delay-ms", "Largest ACK delay of the peer taken off RTT samples"),
+    ("initial-pto-ms", "PTO before the first RTT sample"),
+    ("max-pto-ms", "Largest PTO"),
+    ("backoff-above-bandwidth", "Congestion window factor on loss when sending faster than the estimated bandwidth"),
+    ("backoff-below-bandwidth", "Congestion window factor on loss when sending slower than the estimated bandwidth"),
+];
+
+/// The settings given in a file or on the command line, the others are left alone.
+#[derive(Deserialize, Default, Debug)]
+#[serde(deny_unknown_fields)]
+struct Settings {
+    send_buffer_capacity: Option<usize>,
+    max_datagram_size: Option<usize>,
+    initial_window: Option<usize>,
+    minimum_window: Option<usize>,
+    max_ack_delay_ms: Option<u64>,
+    peer_max_ack_delay_ms: Option<u64>,
+    initial_pto_ms: Option<u64>,
+    max_pto_ms: Option<u64>,
+    backoff_above_bandwidth: Option<f64>,
+    backoff_below_bandwidth: Option<f64>,
+}
+
+impl Settings {
+    fn from_args(args: &ArgMatches) -> Result<Settings, ConfigError> {
+        Ok(Settings {
+            send_buffer_capacity: option(args, "send-buffer-capacity")?,
+            max_datagram_size: option(args, "max-datagram-size")?,
+            initial_window: option(args, "initial-window")?,
+            minimum_window: option(args, "minimum-window")?,
+            max_ack_delay_ms: option(args, "max-ack-delay-ms")?,
+            peer_max_ack_delay_ms: option(args, "peer-max-ack-delay-ms")?,
+            initial_pto_ms: option(args, "initial-pto-ms")?,
+            max_pto_ms: option(args, "max-pto-ms")?,
+            backoff_above_bandwidth: option(args, "backoff-above-bandwidth")?,
+            backoff_below_bandwidth: option(args, "backoff-below-bandwidth")?,
+        })
+    }
+    fn apply(self, mut builder: ConnectionConfigBuilder) -> ConnectionConfigBuilder {
+        if let Some(bytes) = self.send_buffer_capacity { builder = builder.send_buffer_capacity(bytes); }
+        if let Some(bytes) = self.max_datagram_size { builder = builder.max_datagram_size(bytes); }
+        if let Some(bytes) = self.initial_window { builder = builder.initial_window(bytes); }
+        if let Some(bytes) = self.minimum_window { builder = builder.minimum_window(bytes); }
+        if let Some(ms) = self.max_ack_delay_ms { builder = builder.max_ack_delay(Duration::from_millis(ms)); }
+        if let Some(ms) = self.peer_max_ack_delay_ms { builder = builder.peer_max_ack_delay(Duration::from_millis(ms)); }
+        if let Some(ms) = self.initial_pto_ms { builder = builder.initial_PTO(Duration::from_millis(ms)); }
+        if let Some(ms) = self.max_pto_ms { builder = builder.max_PTO(Duration::from_millis(ms)); }
+        if let Some(factor) = self.backoff_above_bandwidth { builder = builder.backoff_above_bandwidth(factor); }
+        if let Some(factor) = self.backoff_below_bandwidth { builder = builder.backoff_below_bandwidth(factor); }
+        builder
+    }
+}
+
+/// The value of the option `name` if given.
+fn option<T: FromStr>(args: &ArgMatches, name: &str) -> Result<Option<T>, ConfigError> {
+    match args.value_of(name) {
+        Some(value) => value.parse().map(Some).map_err(|_| ConfigError::Parse(format!("--{}: {} is not a valid value", name, value))),
+        None => Ok(None),
+    }
+}
@@ -0,0 +1,196 @@
+//! A connection used like a `TcpStream`: `Write` on the side that connects, `Read` on the side that accepts.
+//! It moves the protocol along whenever it is called, so datagrams are exchanged and timers run while the
+//! application reads or writes. The side that connects has a socket of its own, connections accepted by a
+//! `Listener` share its socket.
+
+use crate::config::ConnectionConfig;
+use crate::endpoint::Endpoint;
+use crate::error::{Error, Result};
+use crate::event_loop::{EventLoop, RawFd};
+use crate::protocol::State;
+use crate::udp::{self, UdpDriver};
+use std::io::{self, Cursor, Read, Write};
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use std::cmp;
+
+pub struct Connection {
+    endpoint: Arc<Endpoint>,
+    peer: SocketAddr,
+    /// Wait on the socket from the thread using this connection.
+    event_loop: EventLoop,
+    /// Data flows one way, from the side that connected to the side that accepted.
+    sending: bool,
+    nonblocking: bool,
+    /// Data taken from the state that did not fit in the buffer of the last read.
+    unread: Cursor<Vec<u8>>,
+}
+
+impl Connection {
+    /// Connect to the receiver at `addr` from a random local port, and wait for the handshake to complete.
+    /// Fails with `ConnectionRefused` if nothing listens at `addr`.
+    pub fn connect<A: ToSocketAddrs>(addr: A, config: ConnectionConfig) -> Result<Connection> {
+        let peer = match addr.to_socket_addrs()?.next() {
+            Some(peer) => peer,
+            None => return Err(io::Error::new(io::ErrorKind::InvalidInput, "no address to connect to").into()),
+        };
+        let socket = udp::bind_socket(if peer.is_ipv4() { "0.0.0.0" } else { "[::]" })?;
+        socket.connect(peer)?;
+        debug!("Receiver connected");
+        let endpoint = Endpoint::new(UdpDriver::new(socket), config, false);
+        {
+            let mut inner = endpoint.lock();
+            let mut state = inner.new_state(Some(peer));
+            state.connect();
+            inner.connections.insert(peer, state);
+        }
+        let mut connection = Connection::new(endpoint, peer, true)?;
+        while !connection.with_state(|state| state.established) {
+            connection.drive(true)?;
+        }
+        Ok(connection)
+    }
+    /// The connection to `peer` on the socket of `endpoint`, which already holds its state.
+    pub(crate) fn new(endpoint: Arc<Endpoint>, peer: SocketAddr, sending: bool) -> Result<Connection> {
+        let event_loop = EventLoop::new(&endpoint.lock().driver.socket)?;
+        Ok(Connection { endpoint, peer, event_loop, sending, nonblocking: false, unread: Cursor::new(Vec::new()) })
+    }
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer
+    }
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.endpoint.lock().driver.socket.local_addr()
+    }
+    /// In nonblocking mode reads, writes and `flush` take what the network has delivered so far and return
+    /// `WouldBlock` instead of waiting for more. `wait` then sleeps until it is worth trying again.
+    pub fn set_nonblocking(&mut self, nonblocking: bool) {
+        self.nonblocking = nonblocking;
+    }
+    /// Also wake up `wait` when `fd` is readable, as long as `interested` is set. Lets a nonblocking
+    /// connection wait on its input at the same time.
+    pub fn watch_input(&mut self, fd: RawFd, interested: bool) -> Result<()> {
+        Ok(self.event_loop.watch_input(fd, interested)?)
+    }
+    /// Handle what the network delivered, or if there is nothing, block until a datagram arrives, a timer is
+    /// due or the watched input is readable.
+    pub fn wait(&mut self) -> Result<()> {
+        self.drive(true)
+    }
+    /// End the stream: once everything written so far has arrived, the peer reads to the end. Writing after
+    /// this fails. Nothing to do on the receiving side.
+    pub fn shutdown(&mut self) -> Result<()> {
+        if self.sending {
+            self.with_state(|state| state.finish_data());
+            self.drive(false)?;
+        }
+        Ok(())
+    }
+    /// Close the connection and wait for the peer to agree. The sending side ends the stream and waits for the
+    /// receiver to get all of it, the receiving side closes right away. The CLOSE is sent up to three times.
+    pub fn close(&mut self) -> Result<()> {
+        match self.close_handshake() {
+            // Our CLOSE reached a peer that is done and has closed its socket already.
+            Err(Error::ConnectionRefused) if self.with_state(|state| state.closing.is_some()) => Ok(()),
+            result => result,
+        }
+    }
+    fn close_handshake(&mut self) -> Result<()> {
+        let mut close_attempt = 0;
+        if self.sending {
+            self.shutdown()?;
+            while self.with_state(|state| state.closing.is_none()) {
+                self.drive(true)?;
+            }
+        } else {
+            self.with_state(|state| state.close());
+            self.drive(false)?;
+            close_attempt += 1;
+        }
+        let mut timer = Instant::now();
+        while self.with_state(|state| state.connected) && close_attempt < 3 {
+            let close_timeout = cmp::max(2 * Duration::from_nanos(self.with_state(|state| state.rtt.smoothed_RTT())), Duration::from_millis(100));
+            self.endpoint.drive(&mut self.event_loop, true, Some(timer + close_timeout))?;
+            self.endpoint.lock().take_error(&self.peer)?;
+            if Instant::now() - timer > close_timeout {
+                self.with_state(|state| state.close());
+                self.drive(false)?;
+                close_attempt += 1;
+                timer = Instant::now();
+            }
+        }
+        Ok(())
+    }
+    /// Run `f` on the state of this connection.
+    fn with_state<T, F: FnOnce(&mut State) -> T>(&self, f: F) -> T {
+        f(self.endpoint.lock().connections.get_mut(&self.peer).expect("Connection state missing"))
+    }
+    /// Exchange datagrams and run the timers that are due. With `block`, sleep until a datagram arrives or the
+    /// next timer is due if nothing was received. Fails if sending to the peer did.
+    fn drive(&mut self, block: bool) -> Result<()> {
+        self.endpoint.drive(&mut self.event_loop, block, None)?;
+        self.endpoint.lock().take_error(&self.peer)
+    }
+    /// Move the connection along while the caller waits for something: sleep in blocking mode, and in
+    /// nonblocking mode take what is there once and then give up with `WouldBlock`.
+    fn make_progress(&mut self, polled: &mut bool) -> Result<()> {
+        if self.nonblocking && *polled { return Err(Error::Io(io::ErrorKind::WouldBlock.into())); }
+        *polled = true;
+        self.drive(!self.nonblocking)
+    }
+}
+
+impl Read for Connection {
+    /// Read the data the sender wrote, in order. Returns 0 at the end of the stream, and on the sending side.
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.sending { return Ok(0); }
+        let mut polled = false;
+        loop {
+            if self.unread.position() < self.unread.get_ref().len() as u64 { return self.unread.read(buf); }
+            if let Some(data) = self.with_state(|state| state.read_data()) {
+                self.unread = Cursor::new(data);
+                continue;
+            }
+            if self.with_state(|state| state.all_data_received() || !state.connected) { return Ok(0); }
+            self.make_progress(&mut polled)?;
+        }
+    }
+}
+
+impl Write for Connection {
+    /// Queue as much of `buf` as the send buffer has room for, waiting for room if there is none.
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() { return Ok(0); }
+        let mut polled = false;
+        loop {
+            let sending = self.sending;
+            let written = self.with_state(|state| {
+                if !sending || state.send_state.buffer.is_finished() || state.closing.is_some() { return None; }
+                Some(state.write_data(buf))
+            });
+            match written {
+                None => return Err(io::Error::new(io::ErrorKind::BrokenPipe, "the stream is no longer open for writing")),
+                Some(0) => self.make_progress(&mut polled)?,
+                Some(written) => {
+                    self.drive(false)?;
+                    return Ok(written);
+                }
+            }
+        }
+    }
+    /// Wait until everything written so far has been acknowledged by the receiver.
+    fn flush(&mut self) -> io::Result<()> {
+        let mut polled = false;
+        while self.with_state(|state| !state.all_data_acked() && state.closing.is_none()) {
+            self.make_progress(&mut polled)?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Connection {
+    /// Forget the state, later datagrams from the peer are dropped. Call `close` first to end it cleanly.
+    fn drop(&mut self) {
+        self.endpoint.lock().remove(&self.peer);
+    }
+}
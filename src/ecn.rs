@@ -0,0 +1,245 @@
+//! Explicit Congestion Notification on UDP sockets. Only supported on Linux, other platforms fall back to
+//! plain `recv_from` and `send_to`, reporting every datagram received as Not-ECT and marking none sent.
+
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum EcnCodepoint {
+    NotEct,
+    Ect1,
+    Ect0,
+    Ce,
+}
+
+impl EcnCodepoint {
+    pub fn from_bits(tos: u8) -> EcnCodepoint {
+        match tos & 0b11 {
+            0b01 => EcnCodepoint::Ect1,
+            0b10 => EcnCodepoint::Ect0,
+            0b11 => EcnCodepoint::Ce,
+            _ => EcnCodepoint::NotEct,
+        }
+    }
+    pub fn bits(&self) -> u8 {
+        match self {
+            EcnCodepoint::NotEct => 0b00,
+            EcnCodepoint::Ect1 => 0b01,
+            EcnCodepoint::Ect0 => 0b10,
+            EcnCodepoint::Ce => 0b11,
+        }
+    }
+}
+
+/// Number of received packets carrying each ECN codepoint, as reported in ACK frames.
+#[derive(PartialEq, Clone, Debug, Default)]
+pub struct EcnCounts {
+    pub ect0: u64,
+    pub ect1: u64,
+    pub ce: u64,
+}
+
+impl EcnCounts {
+    pub fn on_packet_received(&mut self, codepoint: EcnCodepoint) {
+        match codepoint {
+            EcnCodepoint::Ect0 => self.ect0 += 1,
+            EcnCodepoint::Ect1 => self.ect1 += 1,
+            EcnCodepoint::Ce => self.ce += 1,
+            EcnCodepoint::NotEct => {}
+        }
+    }
+    pub fn is_empty(&self) -> bool {
+        self.ect0 == 0 && self.ect1 == 0 && self.ce == 0
+    }
+}
+
+/// Ask the kernel to report the codepoint of incoming datagrams. Outgoing ones are marked one at a time by
+/// `send_to`, as each connection on the socket may have its own ECN turned off. Returns whether ECN is available.
+pub fn enable_ecn(socket: &UdpSocket) -> bool {
+    match imp::set_recv_codepoint(socket) {
+        Ok(()) => true,
+        Err(e) => {
+            info!("ECN not available on socket: {}", e);
+            false
+        }
+    }
+}
+
+/// Receive a datagram along with the ECN codepoint it arrived with.
+pub fn recv_from(socket: &UdpSocket, buf: &mut [u8]) -> io::Result<(usize, SocketAddr, EcnCodepoint)> {
+    imp::recv_from(socket, buf)
+}
+
+/// Send a datagram marked with `codepoint`, to `destination` or to the peer the socket is connected to if None.
+pub fn send_to(socket: &UdpSocket, buf: &[u8], destination: Option<SocketAddr>, codepoint: EcnCodepoint) -> io::Result<usize> {
+    imp::send_to(socket, buf, destination, codepoint)
+}
+
+#[cfg(target_os = "linux")]
+pub(crate) use imp::{from_socket_addr, push_cmsg, push_codepoint, read_codepoint, to_socket_addr};
+
+#[cfg(target_os = "linux")]
+mod imp {
+    use super::EcnCodepoint;
+    use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6, UdpSocket};
+    use std::os::unix::io::AsRawFd;
+    use std::{io, mem, ptr};
+
+    fn setsockopt(socket: &UdpSocket, level: libc::c_int, name: libc::c_int, value: libc::c_int) -> io::Result<()> {
+        let result = unsafe {
+            libc::setsockopt(socket.as_raw_fd(), level, name, &value as *const _ as *const libc::c_void, mem::size_of::<libc::c_int>() as libc::socklen_t)
+        };
+        if result < 0 { Err(io::Error::last_os_error()) } else { Ok(()) }
+    }
+
+    pub fn set_recv_codepoint(socket: &UdpSocket) -> io::Result<()> {
+        if socket.local_addr()?.is_ipv6() {
+            setsockopt(socket, libc::IPPROTO_IPV6, libc::IPV6_RECVTCLASS, 1)?;
+            setsockopt(socket, libc::IPPROTO_IP, libc::IP_RECVTOS, 1).ok();
+            Ok(())
+        } else {
+            setsockopt(socket, libc::IPPROTO_IP, libc::IP_RECVTOS, 1)
+        }
+    }
+
+    pub fn recv_from(socket: &UdpSocket, buf: &mut [u8]) -> io::Result<(usize, SocketAddr, EcnCodepoint)> {
+        let mut name: libc::sockaddr_storage = unsafe { mem::zeroed() };
+        let mut iov = libc::iovec { iov_base: buf.as_mut_ptr() as *mut libc::c_void, iov_len: buf.len() };
+        // u64 keeps the control buffer aligned for cmsghdr.
+        let mut control = [0u64; 8];
+        let mut hdr: libc::msghdr = unsafe { mem::zeroed() };
+        hdr.msg_name = &mut name as *mut _ as *mut libc::c_void;
+        hdr.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+        hdr.msg_iov = &mut iov;
+        hdr.msg_iovlen = 1;
+        hdr.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        hdr.msg_controllen = mem::size_of_val(&control) as _;
+        let num_bytes_read = unsafe { libc::recvmsg(socket.as_raw_fd(), &mut hdr, 0) };
+        if num_bytes_read < 0 { return Err(io::Error::last_os_error()); }
+        Ok((num_bytes_read as usize, to_socket_addr(&name)?, read_codepoint(&hdr)))
+    }
+
+    pub fn send_to(socket: &UdpSocket, buf: &[u8], destination: Option<SocketAddr>, codepoint: EcnCodepoint) -> io::Result<usize> {
+        let mut name = destination.map(|destination| from_socket_addr(&destination));
+        let mut iov = libc::iovec { iov_base: buf.as_ptr() as *mut libc::c_void, iov_len: buf.len() };
+        let mut control = [0u64; 8];
+        let mut hdr: libc::msghdr = unsafe { mem::zeroed() };
+        if let Some((name, length)) = &mut name {
+            hdr.msg_name = name as *mut _ as *mut libc::c_void;
+            hdr.msg_namelen = *length;
+        }
+        hdr.msg_iov = &mut iov;
+        hdr.msg_iovlen = 1;
+        let control_length = push_codepoint(&mut control, 0, socket.local_addr()?.is_ipv6(), codepoint);
+        if control_length > 0 {
+            hdr.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+            hdr.msg_controllen = control_length as _;
+        }
+        let sent = unsafe { libc::sendmsg(socket.as_raw_fd(), &hdr, 0) };
+        if sent < 0 { return Err(io::Error::last_os_error()); }
+        Ok(sent as usize)
+    }
+
+    /// Write a control message at `used` bytes into `control`, returns the bytes used with it.
+    pub fn push_cmsg(control: &mut [u64], used: usize, level: libc::c_int, kind: libc::c_int, data: &[u8]) -> usize {
+        let space = unsafe { libc::CMSG_SPACE(data.len() as u32) } as usize;
+        assert!(used + space <= mem::size_of_val(control), "control buffer too small");
+        unsafe {
+            let cmsg = (control.as_mut_ptr() as *mut u8).add(used) as *mut libc::cmsghdr;
+            (*cmsg).cmsg_level = level;
+            (*cmsg).cmsg_type = kind;
+            (*cmsg).cmsg_len = libc::CMSG_LEN(data.len() as u32) as _;
+            ptr::copy_nonoverlapping(data.as_ptr(), libc::CMSG_DATA(cmsg), data.len());
+        }
+        used + space
+    }
+
+    /// The control messages marking a datagram with `codepoint`, none for Not-ECT as that is the default. An IPv6
+    /// socket gets both: the kernel takes IP_TOS for IPv4 peers of a dual-stack socket and ignores the other one.
+    pub fn push_codepoint(control: &mut [u64], used: usize, ipv6: bool, codepoint: EcnCodepoint) -> usize {
+        if codepoint == EcnCodepoint::NotEct { return used; }
+        let value = (codepoint.bits() as libc::c_int).to_ne_bytes();
+        let used = push_cmsg(control, used, libc::IPPROTO_IP, libc::IP_TOS, &value);
+        if ipv6 { push_cmsg(control, used, libc::IPPROTO_IPV6, libc::IPV6_TCLASS, &value) } else { used }
+    }
+
+    pub fn read_codepoint(hdr: &libc::msghdr) -> EcnCodepoint {
+        let mut codepoint = EcnCodepoint::NotEct;
+        let mut cmsg = unsafe { libc::CMSG_FIRSTHDR(hdr) };
+        while !cmsg.is_null() {
+            let (level, kind) = unsafe { ((*cmsg).cmsg_level, (*cmsg).cmsg_type) };
+            let data = unsafe { libc::CMSG_DATA(cmsg) };
+            if level == libc::IPPROTO_IP && (kind == libc::IP_TOS || kind == libc::IP_RECVTOS) {
+                codepoint = EcnCodepoint::from_bits(unsafe { *data });
+            } else if level == libc::IPPROTO_IPV6 && kind == libc::IPV6_TCLASS {
+                let tclass = unsafe { ptr::read_unaligned(data as *const libc::c_int) };
+                codepoint = EcnCodepoint::from_bits(tclass as u8);
+            }
+            cmsg = unsafe { libc::CMSG_NXTHDR(hdr, cmsg) };
+        }
+        codepoint
+    }
+
+    pub fn to_socket_addr(name: &libc::sockaddr_storage) -> io::Result<SocketAddr> {
+        match name.ss_family as libc::c_int {
+            libc::AF_INET => {
+                let addr = unsafe { &*(name as *const _ as *const libc::sockaddr_in) };
+                let ip = Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr));
+                Ok(SocketAddr::V4(SocketAddrV4::new(ip, u16::from_be(addr.sin_port))))
+            }
+            libc::AF_INET6 => {
+                let addr = unsafe { &*(name as *const _ as *const libc::sockaddr_in6) };
+                let ip = Ipv6Addr::from(addr.sin6_addr.s6_addr);
+                Ok(SocketAddr::V6(SocketAddrV6::new(ip, u16::from_be(addr.sin6_port), addr.sin6_flowinfo, addr.sin6_scope_id)))
+            }
+            _ => Err(io::Error::new(io::ErrorKind::InvalidData, "unknown address family")),
+        }
+    }
+
+    /// The other way around, for addressing datagrams. Returns the address and its length.
+    pub fn from_socket_addr(addr: &SocketAddr) -> (libc::sockaddr_storage, libc::socklen_t) {
+        let mut name: libc::sockaddr_storage = unsafe { mem::zeroed() };
+        let length = match addr {
+            SocketAddr::V4(addr) => {
+                let sin = unsafe { &mut *(&mut name as *mut _ as *mut libc::sockaddr_in) };
+                sin.sin_family = libc::AF_INET as libc::sa_family_t;
+                sin.sin_port = addr.port().to_be();
+                sin.sin_addr.s_addr = u32::from(*addr.ip()).to_be();
+                mem::size_of::<libc::sockaddr_in>()
+            }
+            SocketAddr::V6(addr) => {
+                let sin6 = unsafe { &mut *(&mut name as *mut _ as *mut libc::sockaddr_in6) };
+                sin6.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+                sin6.sin6_port = addr.port().to_be();
+                sin6.sin6_addr.s6_addr = addr.ip().octets();
+                sin6.sin6_flowinfo = addr.flowinfo();
+                sin6.sin6_scope_id = addr.scope_id();
+                mem::size_of::<libc::sockaddr_in6>()
+            }
+        };
+        (name, length as libc::socklen_t)
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod imp {
+    use super::EcnCodepoint;
+    use std::io;
+    use std::net::{SocketAddr, UdpSocket};
+
+    pub fn set_recv_codepoint(_socket: &UdpSocket) -> io::Result<()> {
+        Err(io::Error::new(io::ErrorKind::Other, "ECN is only supported on Linux"))
+    }
+
+    pub fn recv_from(socket: &UdpSocket, buf: &mut [u8]) -> io::Result<(usize, SocketAddr, EcnCodepoint)> {
+        let (num_bytes_read, addr) = socket.recv_from(buf)?;
+        Ok((num_bytes_read, addr, EcnCodepoint::NotEct))
+    }
+
+    pub fn send_to(socket: &UdpSocket, buf: &[u8], destination: Option<SocketAddr>, _codepoint: EcnCodepoint) -> io::Result<usize> {
+        match destination {
+            Some(destination) => socket.send_to(buf, destination),
+            None => socket.send(buf),
+        }
+    }
+}
@@ -0,0 +1,203 @@
+//! A UDP socket and the protocol states of the connections on it: the one peer of the sending side, or every
+//! peer of a `Listener`. Whichever thread needs the protocol to move on drives all of them at once: reads the
+//! socket, hands each datagram to the state of the peer it came from, runs the timers and sends what the states
+//! produced. One thread at a time sleeps on the socket, the others wait for it to report what happened.
+
+use crate::config::ConnectionConfig;
+use crate::error::{Error, Result};
+use crate::event_loop::EventLoop;
+use crate::protocol::{Packet, PacketType, State};
+use crate::udp::UdpDriver;
+use rand::Rng;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::{io, mem};
+use std::net::SocketAddr;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// Peers in the middle of the handshake a listener keeps at most, INITs from others are dropped.
+pub const MAX_HANDSHAKING: usize = 256;
+/// Peers a listener holds until they are accepted at most, counting those still in the handshake. New peers are
+/// refused while it is full.
+pub const MAX_INCOMING: usize = 1024;
+/// A peer that has not completed the handshake after this long is forgotten.
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct Endpoint {
+    inner: Mutex<Inner>,
+    /// Signalled when datagrams were handled, or the thread sleeping on the socket woke up.
+    changed: Condvar,
+}
+
+pub struct Inner {
+    pub driver: UdpDriver,
+    pub connections: HashMap<SocketAddr, State>,
+    pub config: ConnectionConfig,
+    /// Take on peers that start a handshake, as a listener.
+    pub accepting: bool,
+    /// Peers in the middle of the handshake.
+    handshaking: Vec<SocketAddr>,
+    /// Peers done with the handshake, not accepted yet.
+    pub incoming: VecDeque<SocketAddr>,
+    /// Connections whose datagrams could not be sent, no longer driven. The error is reported to the connection
+    /// once, None after that.
+    failed: HashMap<SocketAddr, Option<Error>>,
+    /// A thread is sleeping on the socket.
+    polling: bool,
+}
+
+impl Endpoint {
+    pub fn new(driver: UdpDriver, config: ConnectionConfig, accepting: bool) -> Arc<Endpoint> {
+        let inner = Inner {
+            driver,
+            connections: HashMap::new(),
+            config,
+            accepting,
+            handshaking: Vec::new(),
+            incoming: VecDeque::new(),
+            failed: HashMap::new(),
+            polling: false,
+        };
+        Arc::new(Endpoint { inner: Mutex::new(inner), changed: Condvar::new() })
+    }
+    pub fn lock(&self) -> MutexGuard<'_, Inner> {
+        self.inner.lock().expect("Endpoint lock poisoned")
+    }
+    /// Exchange datagrams and run the timers that are due, for every connection. With `block`, sleep if nothing
+    /// was received until a datagram arrives, the next timer or `deadline` is due, the input watched by
+    /// `event_loop` is readable, or the socket has room for the datagrams queued when it had none. Threads that find another one sleeping on the socket wait to hear from it.
+    pub fn drive(&self, event_loop: &mut EventLoop, block: bool, deadline: Option<Instant>) -> Result<()> {
+        let mut inner = self.lock();
+        if inner.poll()? { self.changed.notify_all(); }
+        else if block {
+            let timeout = inner.next_timeout().into_iter().chain(deadline).min();
+            if inner.polling {
+                match timeout {
+                    Some(timeout) => drop(self.changed.wait_timeout(inner, timeout.saturating_duration_since(Instant::now())).expect("Endpoint lock poisoned")),
+                    None => drop(self.changed.wait(inner).expect("Endpoint lock poisoned")),
+                }
+                return Ok(());
+            }
+            event_loop.watch_writable(inner.driver.has_queued())?;
+            inner.polling = true;
+            drop(inner);
+            let result = event_loop.wait(timeout);
+            self.lock().polling = false;
+            self.changed.notify_all();
+            result?;
+        }
+        Ok(())
+    }
+}
+
+impl Inner {
+    /// A new state for a connection, set up as the config says.
+    pub fn new_state(&self, peer: Option<SocketAddr>) -> State {
+        let initial_packet_num = rand::thread_rng().gen_range(1, u8::MAX as u64);
+        let mut state = State::new(initial_packet_num, peer, self.config.clone());
+        state.ecn_enabled = self.driver.ecn_enabled();
+        state
+    }
+    /// Read a batch of datagrams into the connections, run the timers of those that got none, and send what
+    /// every connection produced. A connection whose datagrams cannot be sent fails on its own, the others go on.
+    /// Returns whether a new packet was received or a connection failed.
+    fn poll(&mut self) -> Result<bool> {
+        let mut received_from = HashSet::new();
+        let mut new_peers = Vec::new();
+        let connections = &mut self.connections;
+        let failed = &self.failed;
+        let accepting = self.accepting;
+        self.driver.recv(|datagram, from, codepoint| match connections.get_mut(&from) {
+            Some(_) if failed.contains_key(&from) => {}
+            Some(state) => if state.handle_datagram_with_ecn(datagram, from, codepoint, state.now()) { received_from.insert(from); },
+            None if accepting && is_init(datagram) => new_peers.push((datagram.to_vec(), from, codepoint)),
+            None => debug!("Dropping datagram of size {} from unknown peer {}", datagram.len(), from),
+        })?;
+        for (datagram, from, codepoint) in new_peers {
+            if !self.connections.contains_key(&from) {
+                if self.handshaking.len() >= MAX_HANDSHAKING {
+                    debug!("Dropping INIT from {}, {} handshakes in progress already", from, self.handshaking.len());
+                    continue;
+                }
+                if self.handshaking.len() + self.incoming.len() >= MAX_INCOMING {
+                    debug!("Dropping INIT from {}, {} connections waiting to be accepted already", from, self.incoming.len());
+                    continue;
+                }
+                debug!("New connection from {}", from);
+                let state = self.new_state(None);
+                self.connections.insert(from, state);
+                self.handshaking.push(from);
+            }
+            let state = self.connections.get_mut(&from).unwrap();
+            if state.handle_datagram_with_ecn(&datagram, from, codepoint, state.now()) { received_from.insert(from); }
+        }
+        let mut failed_now = Vec::new();
+        for (peer, state) in self.connections.iter_mut() {
+            if self.failed.contains_key(peer) { continue; }
+            if !received_from.contains(peer) { state.handle_timeout(state.now()); }
+            if let Err(error) = self.driver.flush(state) {
+                info!("Connection to {} failed: {}", peer, error);
+                failed_now.push((*peer, error));
+            }
+        }
+        let failed = !failed_now.is_empty();
+        for (peer, error) in failed_now {
+            if self.handshaking.contains(&peer) || self.incoming.contains(&peer) {
+                // Not accepted yet, nobody to tell.
+                self.remove(&peer);
+            } else {
+                self.failed.insert(peer, Some(error));
+            }
+        }
+        let connections = &self.connections;
+        let expired: Vec<SocketAddr> = self.handshaking.iter().cloned().filter(|peer| {
+            let state = &connections[peer];
+            !state.established && state.now() - state.connection_creation_time.unwrap() > HANDSHAKE_TIMEOUT
+        }).collect();
+        for peer in expired {
+            debug!("Handshake with {} timed out", peer);
+            self.remove(&peer);
+        }
+        let connections = &self.connections;
+        let (established, handshaking): (Vec<SocketAddr>, Vec<SocketAddr>) = mem::take(&mut self.handshaking).into_iter().partition(|peer| connections[peer].established);
+        self.handshaking = handshaking;
+        self.incoming.extend(established);
+        Ok(!received_from.is_empty() || failed)
+    }
+    /// The error the connection to `peer` failed with, if it did. Reported once, later calls fail with
+    /// `NotConnected`.
+    pub fn take_error(&mut self, peer: &SocketAddr) -> Result<()> {
+        match self.failed.get_mut(peer) {
+            None => Ok(()),
+            Some(error) => Err(error.take().unwrap_or_else(|| io::Error::new(io::ErrorKind::NotConnected, "the connection failed").into())),
+        }
+    }
+    /// The earliest timer of any connection.
+    fn next_timeout(&self) -> Option<Instant> {
+        self.connections.values().filter_map(|state| state.next_timeout()).min()
+    }
+    /// Take no more new peers, and forget the ones not accepted yet.
+    pub fn stop_accepting(&mut self) {
+        self.accepting = false;
+        for peer in mem::take(&mut self.handshaking).iter().chain(mem::take(&mut self.incoming).iter()) {
+            self.connections.remove(peer);
+            self.driver.discard(peer);
+        }
+    }
+    /// Forget a connection, datagrams from its peer are dropped from now on.
+    pub fn remove(&mut self, peer: &SocketAddr) {
+        self.connections.remove(peer);
+        self.driver.discard(peer);
+        self.failed.remove(peer);
+        self.handshaking.retain(|handshaking| handshaking != peer);
+        self.incoming.retain(|incoming| incoming != peer);
+    }
+}
+
+/// Whether the datagram starts a handshake, the only packets a listener takes from peers it does not know.
+fn is_init(datagram: &[u8]) -> bool {
+    match Packet::deserialize(&datagram.to_vec()) {
+        Some(packet) => packet.header.packet_type == PacketType::INIT && packet.header.packet_num != 0,
+        None => false,
+    }
+}
@@ -0,0 +1,62 @@
+//! The error of everything that does I/O for a connection: binding, the handshake, reading, writing and closing.
+
+use crate::config::ConfigError;
+use std::{error, fmt, io, result};
+
+pub type Result<T> = result::Result<T, Error>;
+
+#[derive(Debug)]
+pub enum Error {
+    /// Nothing listens at the address of the peer: an ICMP port unreachable came back for a datagram sent to it.
+    ConnectionRefused,
+    /// The settings are invalid, or the file they come from cannot be read.
+    Config(ConfigError),
+    /// Any other failure of the socket or the event loop.
+    Io(io::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::ConnectionRefused => write!(f, "connection refused, nothing is listening at the peer address"),
+            Error::Config(error) => write!(f, "{}", error),
+            Error::Io(error) => write!(f, "I/O error: {}", error),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::ConnectionRefused => None,
+            Error::Config(error) => Some(error),
+            Error::Io(error) => Some(error),
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Error {
+        match error.kind() {
+            io::ErrorKind::ConnectionRefused => Error::ConnectionRefused,
+            _ => Error::Io(error),
+        }
+    }
+}
+
+impl From<ConfigError> for Error {
+    fn from(error: ConfigError) -> Error {
+        Error::Config(error)
+    }
+}
+
+/// For `Read` and `Write`, which return `io::Error`. The kind tells the errors apart again.
+impl From<Error> for io::Error {
+    fn from(error: Error) -> io::Error {
+        match error {
+            Error::Io(error) => error,
+            Error::ConnectionRefused => io::Error::new(io::ErrorKind::ConnectionRefused, Error::ConnectionRefused),
+            Error::Config(error) => io::Error::new(io::ErrorKind::InvalidInput, error),
+        }
+    }
+}
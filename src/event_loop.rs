@@ -0,0 +1,153 @@
+//! Sleep until the socket is readable or the next protocol timer is due, instead of spinning on a
+//! nonblocking socket. Uses level-triggered epoll on Linux, other platforms fall back to short sleeps.
+//! The sender also waits on its input, while the send buffer has room for more of it, and the socket waits to
+//! become writable while datagrams are queued because its buffer was full.
+
+use std::io;
+use std::net::UdpSocket;
+use std::time::Instant;
+
+pub use imp::RawFd;
+
+pub struct EventLoop {
+    poller: imp::Poller,
+}
+
+impl EventLoop {
+    pub fn new(socket: &UdpSocket) -> io::Result<EventLoop> {
+        Ok(EventLoop { poller: imp::Poller::new(socket)? })
+    }
+    /// Also wake up when `fd` is readable, as long as `interested` is set. Turn it off while there is
+    /// nowhere to put what would be read, the wait would return at once otherwise.
+    pub fn watch_input(&mut self, fd: RawFd, interested: bool) -> io::Result<()> {
+        self.poller.watch_input(fd, interested)
+    }
+    /// Also wake up when the socket is writable, as long as `interested` is set. Only while datagrams are
+    /// waiting for room in the socket buffer, it is writable nearly all the time otherwise.
+    pub fn watch_writable(&mut self, interested: bool) -> io::Result<()> {
+        self.poller.watch_writable(interested)
+    }
+    /// Block until the socket or the watched input is ready, or `deadline` has passed, forever if there is
+    /// no deadline. Returns whether either is ready.
+    pub fn wait(&mut self, deadline: Option<Instant>) -> io::Result<bool> {
+        let now = Instant::now();
+        match deadline {
+            Some(deadline) if deadline <= now => Ok(false),
+            Some(deadline) => self.poller.wait(Some(deadline - now)),
+            None => self.poller.wait(None),
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod imp {
+    use std::net::UdpSocket;
+    use std::os::unix::io::AsRawFd;
+    use std::time::Duration;
+    use std::{io, mem};
+
+    pub use std::os::unix::io::RawFd;
+
+    pub struct Poller {
+        epoll_fd: RawFd,
+        socket_fd: RawFd,
+        /// The socket is registered for writability too.
+        writable: bool,
+        /// The input registered with epoll, if any.
+        input: Option<RawFd>,
+        /// The input is a regular file, which epoll refuses but which can always be read without blocking.
+        input_always_ready: bool,
+    }
+
+    impl Poller {
+        pub fn new(socket: &UdpSocket) -> io::Result<Poller> {
+            let epoll_fd = unsafe { libc::epoll_create1(libc::EPOLL_CLOEXEC) };
+            if epoll_fd < 0 { return Err(io::Error::last_os_error()); }
+            let poller = Poller { epoll_fd, socket_fd: socket.as_raw_fd(), writable: false, input: None, input_always_ready: false };
+            let mut event = libc::epoll_event { events: libc::EPOLLIN as u32, u64: 0 };
+            if unsafe { libc::epoll_ctl(epoll_fd, libc::EPOLL_CTL_ADD, socket.as_raw_fd(), &mut event) } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(poller)
+        }
+        pub fn watch_input(&mut self, fd: RawFd, interested: bool) -> io::Result<()> {
+            self.input_always_ready = false;
+            if interested == self.input.is_some() { return Ok(()); }
+            let operation = if interested { libc::EPOLL_CTL_ADD } else { libc::EPOLL_CTL_DEL };
+            let mut event = libc::epoll_event { events: libc::EPOLLIN as u32, u64: 1 };
+            if unsafe { libc::epoll_ctl(self.epoll_fd, operation, fd, &mut event) } < 0 {
+                let error = io::Error::last_os_error();
+                if error.raw_os_error() != Some(libc::EPERM) { return Err(error); }
+                self.input_always_ready = interested;
+                return Ok(());
+            }
+            self.input = if interested { Some(fd) } else { None };
+            Ok(())
+        }
+        pub fn watch_writable(&mut self, interested: bool) -> io::Result<()> {
+            if interested == self.writable { return Ok(()); }
+            let events = if interested { libc::EPOLLIN | libc::EPOLLOUT } else { libc::EPOLLIN };
+            let mut event = libc::epoll_event { events: events as u32, u64: 0 };
+            if unsafe { libc::epoll_ctl(self.epoll_fd, libc::EPOLL_CTL_MOD, self.socket_fd, &mut event) } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            self.writable = interested;
+            Ok(())
+        }
+        pub fn wait(&mut self, timeout: Option<Duration>) -> io::Result<bool> {
+            if self.input_always_ready { return Ok(true); }
+            // epoll only has millisecond resolution, round up so we never wake before the deadline.
+            let timeout_ms = match timeout {
+                Some(timeout) => timeout.as_nanos().div_ceil(1_000_000).min(libc::c_int::MAX as u128) as libc::c_int,
+                None => -1,
+            };
+            let mut event: libc::epoll_event = unsafe { mem::zeroed() };
+            let result = unsafe { libc::epoll_wait(self.epoll_fd, &mut event, 1, timeout_ms) };
+            if result < 0 {
+                let error = io::Error::last_os_error();
+                if error.kind() == io::ErrorKind::Interrupted { return Ok(false); }
+                return Err(error);
+            }
+            Ok(result > 0)
+        }
+    }
+
+    impl Drop for Poller {
+        fn drop(&mut self) {
+            unsafe { libc::close(self.epoll_fd); }
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod imp {
+    use std::net::UdpSocket;
+    use std::time::Duration;
+    use std::{cmp, io, thread};
+
+    #[cfg(unix)]
+    pub use std::os::unix::io::RawFd;
+    /// Inputs are not file descriptors here, and are read between the short sleeps without being watched.
+    #[cfg(not(unix))]
+    pub type RawFd = std::os::raw::c_int;
+
+    pub struct Poller {}
+
+    impl Poller {
+        pub fn new(_socket: &UdpSocket) -> io::Result<Poller> {
+            Ok(Poller {})
+        }
+        /// The short sleeps wake up often enough to read the input as well.
+        pub fn watch_input(&mut self, _fd: RawFd, _interested: bool) -> io::Result<()> {
+            Ok(())
+        }
+        pub fn watch_writable(&mut self, _interested: bool) -> io::Result<()> {
+            Ok(())
+        }
+        pub fn wait(&mut self, timeout: Option<Duration>) -> io::Result<bool> {
+            let max_sleep = Duration::from_millis(1);
+            thread::sleep(cmp::min(timeout.unwrap_or(max_sleep), max_sleep));
+            Ok(true)
+        }
+    }
+}
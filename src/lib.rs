@@ -1,4 +1,19 @@
 pub mod protocol;
+pub mod batch;
+pub mod clock;
+pub mod config;
+pub mod connection;
+pub mod ecn;
+pub mod endpoint;
+pub mod error;
+pub mod event_loop;
+pub mod listener;
+pub mod range_set;
+pub mod reassembly;
+pub mod rtt;
+pub mod send_buffer;
+pub mod sim;
+pub mod udp;
 
 #[macro_use] extern crate bitflags;
 #[macro_use] extern crate bitflags_serde_shim;
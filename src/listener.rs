@@ -0,0 +1,58 @@
+//! The receiving side of many connections at once, on one UDP socket. Datagrams are told apart by the address
+//! they come from, a peer the listener does not know yet becomes a new connection when it sends an INIT, and is
+//! handed out by `accept` once the handshake is done. Each accepted connection can be read from a thread of its
+//! own; whichever thread is waiting on the socket serves them all.
+
+use crate::config::ConnectionConfig;
+use crate::connection::Connection;
+use crate::endpoint::Endpoint;
+use crate::error::Result;
+use crate::event_loop::EventLoop;
+use crate::udp::{self, UdpDriver};
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::sync::Arc;
+
+pub struct Listener {
+    endpoint: Arc<Endpoint>,
+    event_loop: EventLoop,
+}
+
+impl Listener {
+    /// Listen on `addr`, port 0 picks a free one. On an IPv6 address, IPv4 senders are accepted too.
+    pub fn bind<A: ToSocketAddrs>(addr: A, config: ConnectionConfig) -> Result<Listener> {
+        let addr = match addr.to_socket_addrs()?.next() {
+            Some(addr) => addr,
+            None => return Err(io::Error::new(io::ErrorKind::InvalidInput, "no address to listen on").into()),
+        };
+        Listener::new(udp::bind_addr(addr)?, config)
+    }
+    /// Listen on a socket bound already.
+    pub fn new(socket: UdpSocket, config: ConnectionConfig) -> Result<Listener> {
+        socket.set_nonblocking(true)?;
+        let event_loop = EventLoop::new(&socket)?;
+        let endpoint = Endpoint::new(UdpDriver::new(socket), config, true);
+        Ok(Listener { endpoint, event_loop })
+    }
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.endpoint.lock().driver.socket.local_addr()
+    }
+    /// Wait for a sender to complete the handshake, and return the connection to it.
+    pub fn accept(&mut self) -> Result<Connection> {
+        loop {
+            let peer = self.endpoint.lock().incoming.pop_front();
+            if let Some(peer) = peer {
+                debug!("Accepted connection from {}", peer);
+                return Connection::new(self.endpoint.clone(), peer, false);
+            }
+            self.endpoint.drive(&mut self.event_loop, true, None)?;
+        }
+    }
+}
+
+impl Drop for Listener {
+    /// Stop taking new peers. The connections accepted so far go on.
+    fn drop(&mut self) {
+        self.endpoint.lock().stop_accepting();
+    }
+}
@@ -1,39 +1,24 @@
 #![allow(non_snake_case)]
 
+use crate::clock::{Clock, RealClock};
+use crate::config::ConnectionConfig;
+use crate::ecn::{EcnCodepoint, EcnCounts};
+use crate::range_set::RangeSet;
+use crate::reassembly::ReassemblyBuffer;
+use crate::send_buffer::SendBuffer;
+use crate::rtt::RttEstimator;
 use chrono::prelude::*;
 use integer_encoding::*;
-use rand::Rng;
-use std::{cmp, io, str};
-use std::net::UdpSocket;
-use std::collections::{HashMap, VecDeque};
+use std::{cmp, mem, str};
+use std::convert::TryFrom;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::collections::{BTreeMap, HashMap, VecDeque};
 use std::time::{Duration, Instant};
 
-/// Bind to a random port to open a UDP socket. Returns the socket.
-pub fn bind_socket(ip: &str) -> UdpSocket {
-    let mut rng = rand::thread_rng();
-    let mut port = rng.gen_range(29170, 29998);
-    let mut socket_bind;
-    while {
-        socket_bind = UdpSocket::bind(format!("{}:{}", ip, port));
-        socket_bind.is_err()
-    } {
-        port = rng.gen_range(29170, 29998);
-    }
-    eprintln!("{:?} [bound] {}", Local::now(), port);
-    let socket = socket_bind.unwrap();
-    socket.set_nonblocking(true).unwrap();
-    socket
-}
-
-/// Specify port when binding to UDP socket, useful for development.
-pub fn bind_socket_with_port(ip: &str, port: &str) -> UdpSocket {
-    let socket_bind;
-    socket_bind = UdpSocket::bind(format!("{}:{}", ip, port));
-    eprintln!("{:?} [bound] {}", Local::now(), port);
-    let socket = socket_bind.unwrap();
-    socket.set_nonblocking(true).unwrap();
-    socket
-}
+/// Packets numbered further than this past the largest one received are dropped. Far more than the packets a
+/// congestion window holds, and small enough that ACK ranges over the gap stay cheap.
+pub const MAX_PACKET_NUM_GAP: u64 = 1 << 16;
 
 // Reference from rust-quic: https://github.com/flier/rust-quic/tree/develop
 #[derive(Debug)]
@@ -44,56 +29,76 @@ pub struct State {
     pub initial_sent_packet_num: u64,
     pub last_packet_num: u64,
     pub time_of_last_sent_new_packet: Option<Instant>,
+    pub time_of_last_sent_data: Option<Instant>,
+    /// Up to when the idle time has already decayed the congestion window.
+    pub time_of_last_idle_decay: Option<Instant>,
     pub connection_creation_time: Option<Instant>,
     pub sent_largest_ACKed: u64,
     pub sent_largest_lost: u64,
     pub sent_packets: HashMap<u64, SentPacket>,
-    pub lost_packets: VecDeque<u64>,
     pub sent_ack_largest: HashMap<u64, u64>,
     pub sent_end_byte_processed: bool,
 
     pub initial_received_packet_num: u64,
     pub received_largest: u64,
     pub time_of_last_received_packet: Option<Instant>,
-    pub received_packets: HashMap<u64, ReceivedPacket>,
+    pub received_packets: BTreeMap<u64, ReceivedPacket>,
     pub time_of_last_packet_reorder: Option<Instant>,
     pub ack_starting_packet_num: u64,
 
     pub next_byte_offset: usize,
 
-    pub socket: UdpSocket,
+    pub clock: Arc<dyn Clock>,
+    pub peer: Option<SocketAddr>,
+    pub outgoing: VecDeque<Transmit>,
+    pub ecn_enabled: bool,
+    pub ecn_received: EcnCounts,
+    pub ecn_peer_reported: EcnCounts,
 
     pub PTO_amount: u32,
     pub last_PTO: u64,
     pub last_PTO_time: Option<Instant>,
-    pub max_RTT: u64,
-    pub min_RTT: u64,
-    pub latest_RTT: u64,
-    pub smoothed_RTT: u64,
-    pub RTT_variance: u64,
+    pub PTO_probes_pending: u32,
+    /// Packets whose data a probe of the last PTO already carried.
+    pub PTO_probed: Vec<u64>,
+    /// What `poll_transmit` still has to send: an ACK, a CLOSE, and on the sender the INIT.
+    pub ack_pending: bool,
+    pub close_pending: bool,
+    pub init_pending: bool,
+    pub rtt: RttEstimator,
     pub congestion_window: usize,
     pub max_congestion_window: usize,
     pub bytes_in_flight: usize,
     pub slow_start_threshold: usize,
+    pub app_limited: bool,
+    pub pacing_tokens: usize,
+    pub pacing_last_refill: Option<Instant>,
     pub congestion_recovery_start_time: Option<Instant>,
     pub packet_sent: u64,
     pub packet_lost: u64,
 
     pub send_state: StreamSendState,
     pub receive_state: StreamReceiveState,
+    pub config: ConnectionConfig,
 }
 
 #[derive(PartialEq, Clone, Debug)]
 pub struct StreamSendState {
     // pub byte_offset_NEXT: u64,
-    pub sent_data: HashMap<u64, DataSegment>,
-    pub send_queue: VecDeque<Packet>,
+    /// The segments each data packet in flight carries.
+    pub sent_data: HashMap<u64, Vec<DataSegment>>,
+    pub buffer: SendBuffer,
+    /// Bytes acknowledged in any packet, from the start of the send buffer on.
+    pub acked_data: RangeSet,
+    /// Bytes of lost packets not acknowledged since, to be sent again.
+    pub lost_data: RangeSet,
+    /// A lost packet carried no bytes, only the INIT or the end of the stream, and has to be sent again.
+    pub lost_empty_packet: bool,
 }
 
 #[derive(PartialEq, Clone, Debug)]
 pub struct StreamReceiveState {
-    pub received_data: HashMap<u64, Vec<u8>>,
-    pub assembled_data: Vec<u8>,
+    pub received_data: ReassemblyBuffer,
     pub end_received: Option<u64>,
 }
 
@@ -103,6 +108,11 @@ pub struct DataSegment {
     pub length: usize,
 }
 
+/// Bytes a DATA frame at `offset` takes on top of its data.
+fn data_frame_overhead(offset: u64) -> usize {
+    Frame { frame_type: FrameType::DATA, frame_data: DataFrame { end: false, byte_offset: offset, data: Vec::new() }.serialize() }.serialize().len()
+}
+
 #[derive(PartialEq, Clone, Debug)]
 pub struct SentPacket {
     pub packet_num: u64,
@@ -110,6 +120,16 @@ pub struct SentPacket {
     pub time_sent: Instant,
     pub in_flight: bool,
     pub is_ack_only: bool,
+    pub is_app_limited: bool,
+    pub is_ecn_marked: bool,
+}
+
+/// A datagram produced by the protocol core, to be sent by whatever I/O layer drives it.
+#[derive(PartialEq, Clone, Debug)]
+pub struct Transmit {
+    pub destination: SocketAddr,
+    pub contents: Vec<u8>,
+    pub ecn: Option<EcnCodepoint>,
 }
 
 #[derive(PartialEq, Clone, Debug)]
@@ -148,6 +168,12 @@ bitflags! {
 }
 impl_serde_for_bitflags!(IntLength);
 
+/// Decode a varint and its size, `None` if the input ends before it does or it does not fit in a u64.
+fn decode_varint(input: &[u8]) -> Option<(u64, usize)> {
+    let size = input.iter().take(10).position(|byte| byte & 0x80 == 0)? + 1;
+    Some(u64::decode_var(&input[..size]))
+}
+
 #[derive(PartialEq, Clone, Debug)]
 pub struct Header {
     pub packet_type: PacketType,
@@ -163,18 +189,20 @@ impl Header {
 //        debug!("Encoded header size: {}", output.len() as u8);
         output
     }
-    pub fn deserialize(data: &Vec<u8>) -> (Header, usize) {
-        let header_size = data[0] as usize;
+    pub fn deserialize(data: &Vec<u8>) -> Option<(Header, usize)> {
+        let header_size = *data.first()? as usize;
         // debug!("Decoded header size: {}", header_size + 1);
-        let packet_type: PacketType = bincode::deserialize(&data[1..2]).unwrap();
-        let packet_num = u64::decode_var(&data[2..1 + header_size]).0;
-        (
+        if header_size < 2 || data.len() < header_size + 1 { return None; }
+        let packet_type: PacketType = bincode::deserialize(&data[1..2]).ok()?;
+        let (packet_num, size) = decode_varint(&data[2..1 + header_size])?;
+        if size != header_size - 1 { return None; }
+        Some((
             Header {
                 packet_type,
                 packet_num,
             },
             header_size + 1,
-        )
+        ))
     }
 }
 
@@ -196,16 +224,18 @@ impl Frame {
 //        debug!("Encoded frame size: {}", output.len());
         output
     }
-    pub fn deserialize(data: &Vec<u8>) -> (Frame, usize) {
+    pub fn deserialize(data: &Vec<u8>) -> Option<(Frame, usize)> {
+        if data.len() < 3 { return None; }
         let frame_size = u16::from_ne_bytes([data[0], data[1]]) as usize;
         // debug!("Decoded frame size: {}", frame_size + 2);
-        (
+        if frame_size < 1 || data.len() < frame_size + 2 { return None; }
+        Some((
             Frame {
-                frame_type: bincode::deserialize(&data[2..3]).unwrap(),
+                frame_type: bincode::deserialize(&data[2..3]).ok()?,
                 frame_data: data[3..2 + frame_size].into(),
             },
             frame_size + 2,
-        )
+        ))
     }
 }
 
@@ -227,15 +257,15 @@ impl DataFrame {
 //        debug!("Encoded dataframe size: {}", output.len());
         output
     }
-    pub fn deserialize(input: &Vec<u8>) -> DataFrame {
-        let end = input[0] == 1;
-        let byte_offset_decode = u64::decode_var(&input[1..]);
+    pub fn deserialize(input: &Vec<u8>) -> Option<DataFrame> {
+        let end = *input.first()? == 1;
+        let byte_offset_decode = decode_varint(&input[1..])?;
         let data = &input[byte_offset_decode.1 + 1..];
-        DataFrame {
+        Some(DataFrame {
             end: end,
             byte_offset: byte_offset_decode.0,
             data: data.to_vec(),
-        }
+        })
     }
 }
 
@@ -244,48 +274,69 @@ pub struct AckFrame {
     pub largest_ack: u64,
     pub ack_delay: u64,
     pub ack_ranges: Vec<u32>,
+    pub ecn_counts: Option<EcnCounts>,
 }
 
 impl AckFrame {
     pub fn is_acked(&self, packet_num: u64) -> bool {
         if packet_num == self.largest_ack { return true; }
-        let mut acks = Vec::<bool>::new();
+        if packet_num > self.largest_ack { return false; }
+        // The first range starts at the largest ACKed packet, the ranges then alternate between ACKed and not.
+        let distance = self.largest_ack - packet_num;
+        let mut range_start = 0;
         let mut flip = true;
         for range in self.ack_ranges.iter() {
-            acks.extend(c![flip, for x in 0..*range]);
+            if distance < range_start + *range as u64 { return flip; }
+            range_start += *range as u64;
             flip = !flip;
         }
-        for (i, acked) in acks.iter().enumerate() {
-            if packet_num == self.largest_ack - (i + 1) as u64 { return *acked; }
-        }
         false
     }
     pub fn serialize(&self) -> Vec<u8> {
         let mut output = Vec::<u8>::new();
         output.extend(self.largest_ack.encode_var_vec());
         output.extend(self.ack_delay.encode_var_vec());
+        output.extend(self.ack_ranges.len().encode_var_vec());
         for range in self.ack_ranges.iter() {
             output.extend(range.encode_var_vec());
         }
+        if let Some(ecn_counts) = &self.ecn_counts {
+            output.extend(ecn_counts.ect0.encode_var_vec());
+            output.extend(ecn_counts.ect1.encode_var_vec());
+            output.extend(ecn_counts.ce.encode_var_vec());
+        }
         output
     }
-    pub fn deserialize(input: &Vec<u8>) -> AckFrame {
+    pub fn deserialize(input: &Vec<u8>) -> Option<AckFrame> {
         let mut current_offset = 0;
-        let (largest_ack, offset) = u64::decode_var(&input[..]);
+        let (largest_ack, offset) = decode_varint(&input[..])?;
         current_offset += offset;
-        let (ack_delay, offset) = u64::decode_var(&input[current_offset..]);
+        let (ack_delay, offset) = decode_varint(&input[current_offset..])?;
+        current_offset += offset;
+        let (range_count, offset) = decode_varint(&input[current_offset..])?;
         current_offset += offset;
         let mut ack_ranges = Vec::<u32>::new();
-        while current_offset < input.len() {
-            let (range, offset) = u32::decode_var(&input[current_offset..]);
-            ack_ranges.push(range);
+        for _ in 0..range_count {
+            let (range, offset) = decode_varint(&input[current_offset..])?;
+            ack_ranges.push(u32::try_from(range).ok()?);
             current_offset += offset;
         }
-        AckFrame {
+        let mut ecn_counts = None;
+        if current_offset < input.len() {
+            let mut counts = [0u64; 3];
+            for count in counts.iter_mut() {
+                let (value, offset) = decode_varint(&input[current_offset..])?;
+                *count = value;
+                current_offset += offset;
+            }
+            ecn_counts = Some(EcnCounts { ect0: counts[0], ect1: counts[1], ce: counts[2] });
+        }
+        Some(AckFrame {
             largest_ack,
             ack_delay,
             ack_ranges,
-        }
+            ecn_counts,
+        })
     }
 }
 
@@ -312,20 +363,19 @@ impl Packet {
         }
         output
     }
-    pub fn deserialize(input: &Vec<u8>) -> Packet {
-        let (header, header_size) = Header::deserialize(&input);
+    pub fn deserialize(input: &Vec<u8>) -> Option<Packet> {
+        let (header, header_size) = Header::deserialize(&input)?;
         let mut frames = Vec::<Frame>::new();
         let mut current_offset = header_size;
-        while {
-            let (frame, frame_size) = Frame::deserialize(&input[current_offset..].into());
+        while current_offset < input.len() {
+            let (frame, frame_size) = Frame::deserialize(&input[current_offset..].into())?;
             frames.push(frame);
             current_offset += frame_size;
-            current_offset < input.len()
-        } {}
-        Packet {
+        }
+        Some(Packet {
             header,
             frames,
-        }
+        })
     }
     pub fn is_ack_only(&self) -> bool {
         if self.frames.len() == 1 && self.frames[0].frame_type == FrameType::ACK {
@@ -337,110 +387,265 @@ impl Packet {
 }
 
 impl State {
-    pub fn receive_packet(&mut self) -> bool {
-        let mut buf = [0; 2000];
-        let num_bytes_read;
-
-        let result = match self.socket.recv_from(&mut buf) {
-            Ok(n) => Some(n),
-            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => None,
-            Err(e) => {
-                error!("IO error occurred: {}", e);
-                // None
-                std::process::exit(0);
-            }
+    /// Create the state of a new connection. The sender knows its peer up front, the receiver learns it from
+    /// the first datagram it gets.
+    pub fn new(initial_packet_num: u64, peer: Option<SocketAddr>, config: ConnectionConfig) -> State {
+        State::with_clock(initial_packet_num, peer, config, Arc::new(RealClock))
+    }
+    /// Same as `new`, reading time from `clock` instead of the wall clock. Packet numbers start at 1, as the peer
+    /// drops packet 0.
+    pub fn with_clock(initial_packet_num: u64, peer: Option<SocketAddr>, config: ConnectionConfig, clock: Arc<dyn Clock>) -> State {
+        assert!(initial_packet_num > 0, "packet numbers start at 1");
+        let now = clock.now();
+        State {
+            connected: peer.is_some(),
+            closing: None,
+            established: false,
+            initial_sent_packet_num: initial_packet_num,
+            last_packet_num: initial_packet_num - 1,
+            initial_received_packet_num: 0,
+            time_of_last_received_packet: None,
+            time_of_last_sent_new_packet: None,
+            time_of_last_sent_data: None,
+            time_of_last_idle_decay: None,
+            connection_creation_time: Some(now),
+            sent_largest_ACKed: 0,
+            sent_largest_lost: 0,
+            sent_packets: HashMap::new(),
+            sent_ack_largest: HashMap::new(),
+            sent_end_byte_processed: false,
+
+            received_largest: 0,
+            received_packets: BTreeMap::new(),
+            time_of_last_packet_reorder: None,
+            next_byte_offset: 0,
+            ack_starting_packet_num: 0,
+
+            clock,
+            peer,
+            outgoing: VecDeque::new(),
+            ecn_enabled: false,
+            ecn_received: EcnCounts::default(),
+            ecn_peer_reported: EcnCounts::default(),
+
+            PTO_amount: 0,
+            last_PTO: 0,
+            last_PTO_time: None,
+            PTO_probes_pending: 0,
+            PTO_probed: Vec::new(),
+            ack_pending: false,
+            close_pending: false,
+            init_pending: false,
+            rtt: RttEstimator::new(config.peer_max_ack_delay),
+            congestion_window: config.initial_window,
+            max_congestion_window: config.initial_window,
+            bytes_in_flight: 0,
+            slow_start_threshold: usize::MAX,
+            app_limited: false,
+            pacing_tokens: config.initial_window,
+            pacing_last_refill: None,
+            congestion_recovery_start_time: None,
+            packet_sent: 0,
+            packet_lost: 0,
+
+            send_state: StreamSendState {
+                sent_data: HashMap::new(),
+                buffer: SendBuffer::new(config.send_buffer_capacity),
+                acked_data: RangeSet::new(),
+                lost_data: RangeSet::new(),
+                lost_empty_packet: false,
+            },
+            receive_state: StreamReceiveState {
+                received_data: ReassemblyBuffer::new(),
+                end_received: None,
+            },
+            config,
+        }
+    }
+    /// Current time according to the clock of this connection, to pass to the methods below.
+    pub fn now(&self) -> Instant {
+        self.clock.now()
+    }
+    /// Process a datagram received from the network. Returns whether it carried a new packet.
+    pub fn handle_datagram(&mut self, datagram: &[u8], from: SocketAddr, now: Instant) -> bool {
+        self.handle_datagram_with_ecn(datagram, from, EcnCodepoint::NotEct, now)
+    }
+    /// Same as `handle_datagram`, for I/O layers that can read the ECN codepoint of the datagram.
+    pub fn handle_datagram_with_ecn(&mut self, datagram: &[u8], from: SocketAddr, ecn: EcnCodepoint, now: Instant) -> bool {
+        let packet = match Packet::deserialize(&datagram.to_vec()) {
+            Some(packet) => packet,
+            None => { debug!("Dropping malformed datagram of size {} from {}", datagram.len(), from); return false; }
         };
-        if result != None {
-            num_bytes_read = result.unwrap().0;
-        } else {
+        // Packet numbers start at 1, 0 stands for nothing received.
+        if packet.header.packet_num == 0 {
+            debug!("Dropping packet 0 from {}", from);
+            return false;
+        }
+        if packet.header.packet_num.saturating_sub(self.received_largest) > MAX_PACKET_NUM_GAP {
+            debug!("Dropping packet {} from {}, too far past {}", packet.header.packet_num, from, self.received_largest);
             return false;
         }
         if !self.connected {
-            self.socket.connect(result.unwrap().1).expect("connect to sender failed");
+            self.peer = Some(from);
             self.connected = true;
         }
-        let packet = Packet::deserialize(&buf[..num_bytes_read].into());
-        debug!("Received packet size {}: {{packet_type: {:?}, packet_num: {}  Frame_type: {:?}}}", num_bytes_read, packet.header.packet_type, packet.header.packet_num, c![frame.frame_type, for frame in packet.frames.iter()]);
+        debug!("Received packet size {}: {{packet_type: {:?}, packet_num: {}  Frame_type: {:?}}}", datagram.len(), packet.header.packet_type, packet.header.packet_num, c![frame.frame_type, for frame in packet.frames.iter()]);
         let packet_num = packet.header.packet_num;
         if self.received_packets.contains_key(&packet_num) {
             return false;
         }
-        if self.received_largest + 1 != packet_num {
-            self.time_of_last_packet_reorder = Some(Instant::now());
+        // Not acknowledged, so a sender that did go that far ahead sends the data again later.
+        if self.exceeds_receive_window(&packet) {
+            debug!("Dropping packet {} from {}, its data starts past the receive window", packet_num, from);
+            return false;
+        }
+        self.ecn_received.on_packet_received(ecn);
+        if self.received_largest.checked_add(1) != Some(packet_num) {
+            self.time_of_last_packet_reorder = Some(now);
         }
         if self.received_largest < packet_num { self.received_largest = packet_num; }
-        self.received_packets.insert(packet_num, ReceivedPacket { packet_num, time_received: Instant::now(), ack_sent: false, is_ack_only: packet.is_ack_only() });
-        self.time_of_last_received_packet = Some(Instant::now());
+        self.received_packets.insert(packet_num, ReceivedPacket { packet_num, time_received: now, ack_sent: false, is_ack_only: packet.is_ack_only() });
+        self.time_of_last_received_packet = Some(now);
+        // The INIT packets may all be lost, ACK from the first packet that did arrive until one does.
+        if self.ack_starting_packet_num == 0 { self.ack_starting_packet_num = packet_num; }
         if packet.header.packet_type == PacketType::INIT {
             self.initial_received_packet_num = packet_num;
             self.ack_starting_packet_num = packet_num;
         }
-        let should_send_ack = self.should_send_ACK();
+        let should_send_ack = self.should_send_ACK(now);
         for frame in packet.frames.iter() {
             if frame.frame_type == FrameType::DATA {
-                let dataframe = DataFrame::deserialize(&frame.frame_data);
+                let dataframe = match DataFrame::deserialize(&frame.frame_data) {
+                    Some(dataframe) => dataframe,
+                    None => { debug!("Skipping malformed DATA frame in packet {}", packet_num); continue; }
+                };
                 eprintln!("{:?} [recv data] {} ({}) {}", Local::now(), dataframe.byte_offset,dataframe.data.len(), if packet_num == self.received_largest {"ACCEPTED (in-order)"} else {"ACCEPTED (out-of-order)"});
                 self.on_data_received(&dataframe);
             } else if frame.frame_type == FrameType::ACK {
-                let ackframe = AckFrame::deserialize(&frame.frame_data);
-                self.on_ack_received(&ackframe);
+                let ackframe = match AckFrame::deserialize(&frame.frame_data) {
+                    Some(ackframe) => ackframe,
+                    None => { debug!("Skipping malformed ACK frame in packet {}", packet_num); continue; }
+                };
+                self.on_ack_received(&ackframe, now);
             } else if frame.frame_type == FrameType::CLOSE {
                 if self.closing != None { self.connected = false; return true; }
                 debug!("Received close, sending ACK, CLOSE in return.");
-                let mut packet = self.build_new_ack_packet();
+                self.ack_pending = true;
+                self.close_pending = true;
+                return true;
+            }
+        }
+        if should_send_ack { self.ack_pending = true; }
+        // The peer is still sending, so it missed our CLOSE. Send it again.
+        if self.closing.is_some() && !packet.is_ack_only() { self.close_pending = true; }
+        return true;
+    }
+    /// Whether a DATA frame of the packet starts more than `send_buffer_capacity` bytes past those received
+    /// without a gap. A sender with the same configuration never goes further: it only sends from its buffer,
+    /// whose start is a byte the receiver has acknowledged.
+    fn exceeds_receive_window(&self, packet: &Packet) -> bool {
+        let window_end = self.receive_state.received_data.contiguous_end().saturating_add(self.config.send_buffer_capacity as u64);
+        packet.frames.iter().filter(|frame| frame.frame_type == FrameType::DATA).filter_map(|frame| DataFrame::deserialize(&frame.frame_data)).any(|dataframe| dataframe.byte_offset > window_end)
+    }
+    /// Run the timers that are due: delayed ACK, loss detection and PTO.
+    pub fn handle_timeout(&mut self, now: Instant) {
+        if self.should_send_ACK(now) { self.ack_pending = true; }
+        self.detect_packet_lost(now);
+    }
+    /// Start the handshake: the next packet is the INIT, carrying the first of the data written so far.
+    pub fn connect(&mut self) {
+        self.init_pending = true;
+    }
+    /// Close the connection: the next packet is a CLOSE. Call again to retry if the peer does not answer.
+    pub fn close(&mut self) {
+        self.close_pending = true;
+    }
+    /// Take the next datagram to send on the network. The content of each packet is decided here, call until
+    /// it returns None to send everything that is due.
+    pub fn poll_transmit(&mut self, now: Instant) -> Option<Transmit> {
+        if self.outgoing.is_empty() {
+            if let Some(packet) = self.next_packet(now) { self.send_packet(packet, now); }
+        }
+        self.outgoing.pop_front()
+    }
+    /// The send scheduler. Picks what goes out next by priority: a pending ACK, together with a pending CLOSE,
+    /// then lost data, new data, and the PTO probes. Data is held back by the congestion window and the pacing
+    /// budget, and new data by what the application has written; ACKs, CLOSE and probes are not.
+    fn next_packet(&mut self, now: Instant) -> Option<Packet> {
+        if !self.connected { return None; }
+        if self.ack_pending || self.close_pending {
+            let mut packet = if self.ack_pending { self.build_new_ack_packet(now) } else { self.build_new_empty_packet() };
+            self.ack_pending = false;
+            if self.close_pending {
+                debug!("Sending Close packet.");
                 packet.frames.push(self.generate_close_frame());
                 self.closing = Some(packet.header.packet_num);
-                self.send_packet(packet);
-                return true;
+                self.close_pending = false;
             }
+            return Some(packet);
         }
-        if should_send_ack {
-            self.send_ACK();
+        if self.closing.is_some() { return None; }
+        self.cc_validate_after_idle(now);
+        self.pacing_refill(now);
+        // New data is held back until the handshake completes. Data waiting for the pacing budget is not: pacing
+        // spreads the window over the round trip, the sender still uses all of it.
+        self.cc_update_app_limited(!self.established && !self.init_pending && !self.has_lost_data());
+        if self.bytes_in_flight + self.config.max_datagram_size <= self.congestion_window && self.pacing_tokens >= self.config.max_datagram_size {
+            let packet = if self.has_lost_data() {
+                let mut lost_data = mem::take(&mut self.send_state.lost_data);
+                let packet = self.build_new_data_packet_from_ranges(&mut lost_data);
+                self.send_state.lost_data = lost_data;
+                Some(packet)
+            } else if self.init_pending || (self.established && self.has_new_data()) {
+                self.init_pending = false;
+                Some(self.build_new_data_packet())
+            } else {
+                None
+            };
+            if let Some(packet) = packet {
+                self.pacing_tokens = self.pacing_tokens.saturating_sub(packet.len());
+                return Some(packet);
+            }
         }
-        return true;
+        if self.PTO_probes_pending > 0 { return Some(self.build_PTO_probe(now)); }
+        None
     }
-    pub fn should_send_ACK(&mut self) -> bool {
+    pub fn should_send_ACK(&mut self, now: Instant) -> bool {
         let mut ack_skipped = false;
         if self.received_largest == 0 || self.connected == false { return false; }
-        if self.time_of_last_packet_reorder != None && (self.time_of_last_packet_reorder.unwrap().elapsed().as_nanos() as u64) < (1 / 8 * self.smoothed_RTT) { debug!("Sending ACK because of packet reorder."); return true; }
-        for packet_num in c![x, for x in self.ack_starting_packet_num..self.received_largest+1] {
-            match self.received_packets.get(&packet_num) {
-                None => {}
-                Some(received) => {
-                    if received.is_ack_only && (received.time_received.elapsed().as_nanos() as u64 <= self.smoothed_RTT) { return false; }
-                    if received.ack_sent == false && self.established == false { debug!("Sending ACK because not established."); return true; }
-                    if received.ack_sent == false && received.time_received.elapsed() > Duration::from_millis(5) {
-                        debug!("Sending ACK because max_ack_delay reached."); 
-                        return true;
-                    } else if received.ack_sent == false {
-                        if ack_skipped { debug!("Sending ACK because 2 unacked packets."); return true; } else {
-                            ack_skipped = true;
-                        }
-                    }
+        if self.time_of_last_packet_reorder.is_some() && ((now - self.time_of_last_packet_reorder.unwrap()).as_nanos() as u64) < (1 / 8 * self.rtt.smoothed_RTT()) { debug!("Sending ACK because of packet reorder."); return true; }
+        for (_, received) in self.received_packets.range(self.ack_starting_packet_num..) {
+            if received.is_ack_only && ((now - received.time_received).as_nanos() as u64) < self.rtt.smoothed_RTT() { return false; }
+            if !received.ack_sent && !self.established { debug!("Sending ACK because not established."); return true; }
+            if !received.ack_sent && now - received.time_received > self.config.max_ack_delay {
+                debug!("Sending ACK because max_ack_delay reached."); 
+                return true;
+            } else if !received.ack_sent {
+                if ack_skipped { debug!("Sending ACK because 2 unacked packets."); return true; } else {
+                    ack_skipped = true;
                 }
             }
         }
         return false;
     }
-    pub fn send_ACK(&mut self) {
-        let packet = self.build_new_ack_packet();
-        self.send_packet(packet);
-    }
-    pub fn send_PTO(&mut self) {
-        let mut packet = self.build_new_ack_packet();
-        packet.frames.push(Frame {frame_type: FrameType::PING, frame_data: vec![0]});
-        self.send_packet(packet);
-    }
-    pub fn send_all_in_queue(&mut self) {
-        while self.bytes_in_flight < self.congestion_window && self.send_state.send_queue.len() != 0 {
-            if self.send_a_packet_in_queue() == false {break;}
-        }
-    }
-    pub fn send_new_data(&mut self, data: &Vec<u8>) {
-        // if self.send_state.send_queue.len() != 0 { info!("Send queue not empty when calling send_new_data()"); return; }
-        while self.bytes_in_flight < self.congestion_window - 1472 && !self.sent_end_byte_processed {
-            self.build_new_data_packet(data);
-            if self.send_a_packet_in_queue() == false {break;}
+    /// A probe for the last PTO. It carries the oldest unacknowledged data not probed yet, new data if everything
+    /// in flight has been probed, and falls back to ACK + PING otherwise. Probes may exceed the congestion window.
+    pub fn build_PTO_probe(&mut self, now: Instant) -> Packet {
+        self.PTO_probes_pending -= 1;
+        let mut unacked = c![*packet_num, for packet_num in self.sent_packets.keys(), if self.send_state.sent_data.contains_key(packet_num) && !self.PTO_probed.contains(packet_num)];
+        unacked.sort();
+        if !unacked.is_empty() {
+            debug!("Sending PTO probe with data of packet {}.", unacked[0]);
+            let mut ranges = self.unacked_ranges(&self.send_state.sent_data[&unacked[0]]);
+            self.PTO_probed.push(unacked[0]);
+            self.build_new_data_packet_from_ranges(&mut ranges)
+        } else if self.has_new_data() {
+            debug!("Sending PTO probe with new data.");
+            self.build_new_data_packet()
+        } else {
+            let mut packet = self.build_new_ack_packet(now);
+            packet.frames.push(Frame {frame_type: FrameType::PING, frame_data: vec![0]});
+            packet
         }
     }
     pub fn build_new_empty_packet(&mut self) -> Packet {
@@ -454,20 +659,42 @@ impl State {
             frames: vec![],
         }
     }
-    pub fn build_new_data_packet(&mut self, data: &Vec<u8>) -> DataSegment {
+    /// Queue the application's bytes for sending, as many as the send buffer has room for. Returns how many
+    /// were taken.
+    pub fn write_data(&mut self, data: &[u8]) -> usize {
+        self.send_state.buffer.write(data)
+    }
+    /// Mark the end of the stream: the receiver is told once everything written so far has been sent.
+    pub fn finish_data(&mut self) {
+        self.send_state.buffer.finish();
+    }
+    /// How many more bytes `write_data` would take.
+    pub fn send_buffer_space(&self) -> usize {
+        self.send_state.buffer.free_space()
+    }
+    /// Whether there are bytes never sent yet, or the end of the stream still has to be sent.
+    pub fn has_new_data(&self) -> bool {
+        !self.sent_end_byte_processed && (self.next_byte_offset < self.send_state.buffer.end() as usize || self.send_state.buffer.is_finished())
+    }
+    /// Whether every byte written so far has been acknowledged, and freed from the send buffer.
+    pub fn all_data_acked(&self) -> bool {
+        self.send_state.buffer.start() == self.send_state.buffer.end()
+    }
+    pub fn build_new_data_packet(&mut self) -> Packet {
         let offset = self.next_byte_offset;
         let header = Header {
             packet_type: if self.established == true { PacketType::NORM } else { PacketType::INIT },
             packet_num: self.last_packet_num + 1,
         };
-        let avaliable_bytes = 1472 - header.serialize().len() - 4 - (offset as u64).encode_var_vec().len();
-        let end = offset + avaliable_bytes >= data.len();
+        let avaliable_bytes = self.config.max_datagram_size - header.serialize().len() - data_frame_overhead(offset as u64);
+        let data = self.send_state.buffer.get(offset as u64, avaliable_bytes);
+        let data_end = offset + data.len();
+        let end = self.send_state.buffer.is_finished() && data_end as u64 == self.send_state.buffer.end();
         if end { self.sent_end_byte_processed = true; }
-        let data_end = cmp::min(data.len(), offset + avaliable_bytes);
         let dataframe = DataFrame {
             end,
             byte_offset: offset as u64,
-            data: data[offset..data_end].to_vec(),
+            data,
         };
         let frame = Frame {
             frame_type: FrameType::DATA,
@@ -477,49 +704,69 @@ impl State {
         self.next_byte_offset += data_end - offset;
         let data_segment = DataSegment { byte_offset: offset as u64, length: data_end - offset };
         debug!("Constructing packet from new segment: {:?}", data_segment);
-        self.send_state.sent_data.insert(header.packet_num, DataSegment { byte_offset: offset as u64, length: data_end - offset });
-        self.send_state.send_queue.push_back(Packet { header, frames: vec![frame] });
-        data_segment
+        self.send_state.sent_data.insert(header.packet_num, vec![data_segment]);
+        Packet { header, frames: vec![frame] }
     }
-    pub fn build_new_data_packet_from_segment(&mut self, data: &Vec<u8>, data_segment: DataSegment) -> DataSegment {
-        let offset = data_segment.byte_offset as usize;
+    /// Build a packet of DATA frames for the lowest ranges in `ranges`, taking out the bytes that fit. It carries
+    /// an empty frame at the next new byte if nothing else, so a lost INIT or end of the stream is sent again.
+    pub fn build_new_data_packet_from_ranges(&mut self, ranges: &mut RangeSet) -> Packet {
         let header = Header {
             packet_type: if self.established == true { PacketType::NORM } else { PacketType::INIT },
             packet_num: self.last_packet_num + 1,
         };
-        let avaliable_bytes = 1472 - header.serialize().len() - 4 - (offset as u64).encode_var_vec().len();
-        if avaliable_bytes < data_segment.length { 
-            info!("Packet does not have enough space to send this data segment");
-            let new_segment = DataSegment {
-                byte_offset: data_segment.byte_offset + avaliable_bytes as u64,
-                length: data_segment.length - avaliable_bytes,
-            };
-            self.build_new_data_packet_from_segment(data, new_segment);
+        let mut space = self.config.max_datagram_size - header.serialize().len();
+        let mut frames = Vec::new();
+        let mut data_segments = Vec::new();
+        while let Some((start, end)) = ranges.first() {
+            let overhead = data_frame_overhead(start);
+            if space <= overhead { break; }
+            let data = self.send_state.buffer.get(start, cmp::min((end - start) as usize, space - overhead));
+            ranges.remove(start, start + data.len() as u64);
+            space -= overhead + data.len();
+            data_segments.push(DataSegment { byte_offset: start, length: data.len() });
+            frames.push(self.data_frame(start, data));
         }
-        let end = offset + data_segment.length >= data.len();
-        let data_end = cmp::min(cmp::min(data.len(), offset + data_segment.length), offset + avaliable_bytes);
-        let dataframe = DataFrame {
-            end,
-            byte_offset: offset as u64,
-            data: data[offset..data_end].to_vec(),
-        };
-        let frame = Frame {
-            frame_type: FrameType::DATA,
-            frame_data: dataframe.serialize(),
-        };
-        let data_segment = DataSegment { byte_offset: offset as u64, length: data_end - offset };
-        debug!("Constructing packet from lost segment: {:?}", data_segment);
+        if (frames.is_empty() || self.send_state.lost_empty_packet) && space >= data_frame_overhead(self.next_byte_offset as u64) {
+            self.send_state.lost_empty_packet = false;
+            data_segments.push(DataSegment { byte_offset: self.next_byte_offset as u64, length: 0 });
+            frames.push(self.data_frame(self.next_byte_offset as u64, Vec::new()));
+        }
+        debug!("Constructing packet from lost segments: {:?}", data_segments);
         self.last_packet_num += 1;
-        self.send_state.sent_data.insert(header.packet_num, DataSegment { byte_offset: offset as u64, length: data_end - offset });
-        self.send_state.send_queue.push_front(Packet { header, frames: vec![frame] });
-        data_segment
+        self.send_state.sent_data.insert(header.packet_num, data_segments);
+        Packet { header, frames }
     }
-    pub fn build_new_ack_packet(&mut self) -> Packet {
+    /// A DATA frame for `data` at `offset`, marked as the end if it reaches the end of the stream.
+    fn data_frame(&mut self, offset: u64, data: Vec<u8>) -> Frame {
+        let end = self.send_state.buffer.is_finished() && offset + data.len() as u64 == self.send_state.buffer.end();
+        // The bytes were sent before the end of the stream was known, they carry the end now.
+        if end { self.sent_end_byte_processed = true; }
+        Frame {
+            frame_type: FrameType::DATA,
+            frame_data: DataFrame { end, byte_offset: offset, data }.serialize(),
+        }
+    }
+    /// The parts of the segments that have not been acknowledged in some other packet.
+    pub fn unacked_ranges(&self, data_segments: &[DataSegment]) -> RangeSet {
+        let mut ranges = RangeSet::new();
+        for data_segment in data_segments.iter() {
+            ranges.insert(data_segment.byte_offset, data_segment.byte_offset + data_segment.length as u64);
+        }
+        for (start, end) in self.send_state.acked_data.iter() {
+            ranges.remove(start, end);
+        }
+        ranges
+    }
+    /// Whether there are lost bytes, or a lost empty packet, still to be sent again.
+    pub fn has_lost_data(&self) -> bool {
+        !self.send_state.lost_data.is_empty() || self.send_state.lost_empty_packet
+    }
+    pub fn build_new_ack_packet(&mut self, now: Instant) -> Packet {
         let header = Header {
             packet_type: if self.established == true { PacketType::NORM } else { PacketType::INIT },
             packet_num: self.last_packet_num + 1,
         };
-        let ackframe = self.generate_ackframe();
+        let ackframe = self.generate_ackframe(now);
         self.sent_ack_largest.insert(header.packet_num, ackframe.largest_ack);
         let frame = Frame {
             frame_type: FrameType::ACK,
@@ -531,79 +778,84 @@ impl State {
             frames: vec![frame],
         }
     }
-    pub fn send_a_packet_in_queue(&mut self) -> bool {
-//        debug!("Checking the queue to send packet: {} packets", self.send_state.send_queue.len());
-        if self.send_state.send_queue.len() == 0 { return false; }
-        let packet = self.send_state.send_queue.pop_front().unwrap();
-        if self.bytes_in_flight + packet.len() > self.congestion_window {
-            self.send_state.send_queue.push_front(packet);
-            // debug!("Queue is full, not sending any more.");
-            return false;
-        }
-        self.send_packet(packet);
-        return true;
-    }
-    pub fn generate_ackframe(&mut self) -> AckFrame {
+    pub fn generate_ackframe(&mut self, now: Instant) -> AckFrame {
         self.received_packets.get_mut(&self.received_largest).unwrap().ack_sent = true;
+        debug!("Generating ACK frame, largest packet: {}", self.ack_starting_packet_num);
+        // Only the packets received are walked, the gaps between them become the missing ranges.
         let mut ack_ranges = Vec::new();
-        let mut current_num = self.received_largest - 1;
         let mut current_counter = 1;
-        let mut flip = true;
-        debug!("Generating ACK frame, largest packet: {}", self.ack_starting_packet_num);
-        while current_num >= self.ack_starting_packet_num {
-            let result = self.received_packets.contains_key(&current_num);
-            if result == true {
-                self.received_packets.get_mut(&current_num).unwrap().ack_sent = true;
-            }
-            if result == flip {
+        let mut lowest = self.received_largest;
+        for (&packet_num, received) in self.received_packets.range_mut(self.ack_starting_packet_num.min(self.received_largest)..self.received_largest).rev() {
+            received.ack_sent = true;
+            if packet_num + 1 == lowest {
                 current_counter += 1;
             } else {
                 ack_ranges.push(current_counter);
+                ack_ranges.push((lowest - packet_num - 1) as u32);
                 current_counter = 1;
-                flip = !flip;
             }
-            current_num -= 1;
+            lowest = packet_num;
         }
         ack_ranges.push(current_counter);
+        let missing = lowest - cmp::max(self.ack_starting_packet_num, 1).min(lowest);
+        if missing > 0 { ack_ranges.push(missing as u32); }
         AckFrame {
             largest_ack: self.received_largest,
-            ack_delay: self.received_packets.get(&self.received_largest).unwrap().time_received.elapsed().as_nanos() as u64,
+            ack_delay: (now - self.received_packets.get(&self.received_largest).unwrap().time_received).as_nanos() as u64,
             ack_ranges,
+            ecn_counts: if self.ecn_received.is_empty() { None } else { Some(self.ecn_received.clone()) },
         }
     }
-    pub fn send_packet(&mut self, packet: Packet) {
+    pub fn send_packet(&mut self, packet: Packet, now: Instant) {
         if !self.connected { return; }
         let sent_packet = SentPacket {
             packet_num: packet.header.packet_num,
             size: packet.len(),
-            time_sent: Instant::now(),
+            time_sent: now,
             in_flight: true,
             is_ack_only: packet.is_ack_only(),
+            is_app_limited: self.app_limited,
+            is_ecn_marked: self.ecn_enabled,
         };
+        if !sent_packet.is_ack_only { self.time_of_last_sent_data = Some(now); }
         self.sent_packets.insert(packet.header.packet_num, sent_packet.clone()); 
         self.on_packet_sent(sent_packet);
         let packet_bytes = packet.serialize();
-        debug!("Sending packet of size {}.", packet_bytes.len());
-        self.socket.send(&packet_bytes).expect("Send Failed");
+        self.outgoing.push_back(Transmit {
+            destination: self.peer.unwrap(),
+            contents: packet_bytes,
+            ecn: if self.ecn_enabled { Some(EcnCodepoint::Ect0) } else { None },
+        });
         // debug!("Send complete.");
         self.packet_sent += 1;
     }
     pub fn on_packet_sent(&mut self, sent_packet: SentPacket) {
-        self.time_of_last_sent_new_packet = Some(Instant::now());
+        self.time_of_last_sent_new_packet = Some(sent_packet.time_sent);
         self.cc_on_packet_sent(sent_packet.size);
     }
     pub fn on_data_received(&mut self, data_frame: &DataFrame) {
         debug!("Processing DataFrame: {{ end:{}, offset:{} }}", data_frame.end, data_frame.byte_offset);
-        if !self.receive_state.received_data.contains_key(&data_frame.byte_offset) {
-            self.receive_state.received_data.insert(data_frame.byte_offset, data_frame.data.clone());
+        let end = match data_frame.byte_offset.checked_add(data_frame.data.len() as u64) {
+            Some(end) => end,
+            None => { debug!("Dropping DATA frame past the largest offset: {}", data_frame.byte_offset); return; }
+        };
+        if let Err(conflict) = self.receive_state.received_data.insert(data_frame.byte_offset, &data_frame.data) {
+            error!("Conflicting data at byte {}, keeping what was received first.", conflict.offset);
+            return;
         }
         // debug!("Data: {}", str::from_utf8(&data_frame.data).unwrap());
         if data_frame.end {
-            self.receive_state.end_received = Some(data_frame.byte_offset + data_frame.data.len() as u64);
+            self.receive_state.end_received = Some(end);
         }
     }
-    pub fn on_ack_received(&mut self, ack_frame: &AckFrame) {
+    pub fn on_ack_received(&mut self, ack_frame: &AckFrame, now: Instant) {
         debug!("Processing AckFrame: {:?}", ack_frame);
+        // Forged, acting on it would declare every packet sent from now on lost as soon as it goes out.
+        if ack_frame.largest_ack > self.last_packet_num {
+            debug!("Ignoring ACK of packet {}, the last one sent is {}", ack_frame.largest_ack, self.last_packet_num);
+            return;
+        }
+        let largest_increased = ack_frame.largest_ack > self.sent_largest_ACKed;
         if self.sent_largest_ACKed == 0 {
             self.sent_largest_ACKed = ack_frame.largest_ack;
         } else {
@@ -613,82 +865,113 @@ impl State {
         let new_latest_ack = self.sent_packets.contains_key(&ack_frame.largest_ack);
         if new_latest_ack {
             self.PTO_amount = 0;
-            self.latest_RTT = (Instant::now() - self.sent_packets.get(&ack_frame.largest_ack).unwrap().time_sent).as_nanos() as u64;
-            self.update_RTT(ack_frame.ack_delay);
+            let latest_RTT = (now - self.sent_packets.get(&ack_frame.largest_ack).unwrap().time_sent).as_nanos() as u64;
+            self.rtt.update(latest_RTT, ack_frame.ack_delay, now);
             if self.established == false { self.established = true; }
         }
         let new_acked_packets = self.get_new_acked_packets(ack_frame);
         if new_acked_packets.len() == 0 { return; }
         for acked_packet in new_acked_packets.iter() {
             self.cc_on_packet_acked(acked_packet);
+            for data_segment in self.send_state.sent_data.remove(&acked_packet.packet_num).unwrap_or_default() {
+                let end = data_segment.byte_offset + data_segment.length as u64;
+                self.send_state.acked_data.insert(data_segment.byte_offset, end);
+                self.send_state.lost_data.remove(data_segment.byte_offset, end);
+            }
         }
-        self.detect_packet_lost();
+        self.release_acked_data();
+        if largest_increased { self.process_ECN(ack_frame, &new_acked_packets, now); }
+        self.detect_packet_lost(now);
     }
-    pub fn congestion_event(&mut self, sent_time: Instant) {
+    /// ECN validation, reference from QUIC RFC. https://quicwg.org/base-drafts/draft-ietf-quic-transport.html
+    /// Marking is turned off if the peer's counts show our ECT(0) marks are not getting through,
+    /// and an increase in CE counts is treated like a packet loss.
+    pub fn process_ECN(&mut self, ack_frame: &AckFrame, acked_packets: &[SentPacket], now: Instant) {
+        if !self.ecn_enabled { return; }
+        let newly_acked_ect0 = acked_packets.iter().filter(|acked| acked.is_ecn_marked).count() as u64;
+        if newly_acked_ect0 == 0 { return; }
+        let counts = match &ack_frame.ecn_counts {
+            Some(counts) => counts.clone(),
+            None => { self.disable_ECN("ACK frame has no ECN counts"); return; }
+        };
+        let previous = self.ecn_peer_reported.clone();
+        if counts.ect0 < previous.ect0 || counts.ect1 < previous.ect1 || counts.ce < previous.ce {
+            self.disable_ECN("ECN counts decreased");
+            return;
+        }
+        // The counts did not decrease, but forged ones can still add up past u64.
+        let newly_reported = match (counts.ect0 - previous.ect0).checked_add(counts.ce - previous.ce) {
+            Some(newly_reported) => newly_reported,
+            None => { self.disable_ECN("ECN counts overflow"); return; }
+        };
+        if newly_reported < newly_acked_ect0 || counts.ect1 > previous.ect1 {
+            self.disable_ECN("ECN marks were changed on path");
+            return;
+        }
+        self.ecn_peer_reported = counts.clone();
+        if counts.ce > previous.ce {
+            let largest_acked = acked_packets.iter().max_by_key(|acked| acked.packet_num).unwrap();
+            debug!("Peer reported {} new CE marks.", counts.ce - previous.ce);
+            self.congestion_event(largest_acked.time_sent, now);
+        }
+    }
+    pub fn disable_ECN(&mut self, reason: &str) {
+        info!("ECN validation failed: {}, disabling ECN.", reason);
+        self.ecn_enabled = false;
+    }
+    pub fn congestion_event(&mut self, sent_time: Instant, now: Instant) {
         if !self.cc_is_in_congestion_recovery(sent_time) {
             debug!("Congestion event started.");
-            self.congestion_recovery_start_time = Some(Instant::now());
-            if self.smoothed_RTT != 0 && self.min_RTT != 0 {
-                debug!("Estimated bandwidth: {}, estimated current throughput: {}",self.estimate_bandWidth(), self.congestion_window as u64 * 100000000 / self.smoothed_RTT);
-                if (self.congestion_window as u64 * 100000000 / self.smoothed_RTT) as f64 >= self.estimate_bandWidth() as f64 {
-                    self.congestion_window = (self.congestion_window as f64 * 0.6) as usize;
+            self.congestion_recovery_start_time = Some(now);
+            if self.rtt.smoothed_RTT() != 0 && self.rtt.min_RTT() != 0 {
+                debug!("Estimated bandwidth: {}, estimated current throughput: {}",self.estimate_bandWidth(), self.congestion_window as u64 * 100000000 / self.rtt.smoothed_RTT());
+                if (self.congestion_window as u64 * 100000000 / self.rtt.smoothed_RTT()) as f64 >= self.estimate_bandWidth() as f64 {
+                    self.congestion_window = (self.congestion_window as f64 * self.config.backoff_above_bandwidth) as usize;
                 } else {
-                    self.congestion_window = (self.congestion_window as f64 * 0.8) as usize;
+                    self.congestion_window = (self.congestion_window as f64 * self.config.backoff_below_bandwidth) as usize;
                 }
             }
-            self.congestion_window = cmp::max(self.congestion_window, 14720);
+            self.congestion_window = cmp::max(self.congestion_window, self.config.minimum_window);
             self.slow_start_threshold = self.congestion_window;
             debug!("Congestion window reduced to {}. Bytes in flight: {}", self.congestion_window, self.bytes_in_flight);
         }
     }
     pub fn get_lost_timeout(&self) -> u64 {
-        let mut output = cmp::max(self.smoothed_RTT, self.latest_RTT);
+        let mut output = cmp::max(self.rtt.smoothed_RTT(), self.rtt.latest_RTT());
         output = cmp::max(output, Duration::from_millis(2).as_nanos() as u64);
-        (output as f64 * 9.0 / 8.0 * (1.0 + self.RTT_variance as f64 / self.smoothed_RTT as f64)) as u64
+        (output as f64 * 9.0 / 8.0 * (1.0 + self.rtt.RTT_variance() as f64 / self.rtt.smoothed_RTT() as f64)) as u64
     }
     pub fn get_PTO(&self) -> u64 {
         let mut output;
-        if self.smoothed_RTT == 0 {
-            output = Duration::from_millis(500).as_nanos() as u64;
+        if self.rtt.smoothed_RTT() == 0 {
+            output = self.config.initial_PTO.as_nanos() as u64;
         } else {
-            output = self.smoothed_RTT + cmp::max(4 * self.RTT_variance, Duration::from_millis(1).as_nanos() as u64) + Duration::from_millis(1).as_nanos() as u64;
+            output = self.rtt.smoothed_RTT() + cmp::max(4 * self.rtt.RTT_variance(), Duration::from_millis(1).as_nanos() as u64) + Duration::from_millis(1).as_nanos() as u64;
         }
         if self.PTO_amount > 0  && self.PTO_amount < 3 {
             output = self.last_PTO * 2;
         }
-        output = cmp::min(output, Duration::from_millis(1200).as_nanos() as u64);
+        output = cmp::min(output, self.config.max_PTO.as_nanos() as u64);
         output
     }
     pub fn get_new_acked_packets(&mut self, ack_frame: &AckFrame) -> Vec<SentPacket> {
-        let mut acked_packets = Vec::<SentPacket>::new();
-        let mut acks = Vec::<bool>::new();
-        let mut flip = true;
-        for range in ack_frame.ack_ranges.iter() {
-            acks.extend(c![flip, for _x in 0..*range]);
-            flip = !flip;
-        }
-        for (i, acked) in acks.iter().enumerate() {
-            if *acked {
-                let this_packet_num = ack_frame.largest_ack - (i as u64);
-//                debug!("Packet {} is consider ACKed.", this_packet_num);
-                match self.sent_packets.remove(&this_packet_num) {
-                    Some(sent_packet) => acked_packets.push(sent_packet),
-                    None => {}
-                };
-            }
-        }
-        acked_packets
+        // Only packets in flight can be newly ACKed, however far the ranges of a malformed ACK reach.
+        let mut acked = c![*packet_num, for packet_num in self.sent_packets.keys(), if ack_frame.is_acked(*packet_num)];
+        acked.sort_unstable_by(|a, b| b.cmp(a));
+        c![self.sent_packets.remove(&packet_num).unwrap(), for packet_num in acked]
     }
-    pub fn assemble_remaining_data(&mut self) -> bool {
-        while self.receive_state.received_data.contains_key(&(self.receive_state.assembled_data.len() as u64)) {
-            self.receive_state.assembled_data.extend(self.receive_state.received_data.get(&(self.receive_state.assembled_data.len() as u64)).unwrap());
-        }
-        if self.receive_state.end_received != None && self.receive_state.end_received.unwrap() == self.receive_state.assembled_data.len() as u64 {
+    /// Take the received bytes that continue the stream, as soon as they are contiguous. They are freed from
+    /// the receive buffer.
+    pub fn read_data(&mut self) -> Option<Vec<u8>> {
+        self.receive_state.received_data.read()
+    }
+    /// Whether every byte up to the end of the stream has arrived, read out or not.
+    pub fn all_data_received(&self) -> bool {
+        if self.receive_state.end_received.is_some() && self.receive_state.end_received.unwrap() == self.receive_state.received_data.contiguous_end() {
             debug!("Data reported as complete.");
-            return true;
-        } else { 
-            // debug!("Assemble data failed, latest offset: {}", self.receive_state.assembled_data.len());
-            return false; 
+            true
+        } else {
+            false
         }
     }
     /// Reference from QUIC RFC. https://quicwg.org/base-drafts/draft-ietf-quic-recovery.html
@@ -713,11 +996,13 @@ impl State {
             return;
         }
         if acked_packet.size <= 20 { return; }
-        if self.smoothed_RTT != 0 && self.min_RTT != 0 {
-            debug!("Estimated bandwidth: {}, estimated current throughput: {}",self.estimate_bandWidth(), self.congestion_window as u64 * 100000000 / self.smoothed_RTT);
-            if (self.congestion_window as u64 * 100000000 / self.smoothed_RTT) as f64 <= self.estimate_bandWidth() as f64 * 0.8 {
-                self.congestion_window += (1472.0 * (acked_packet.size as f64 / self.congestion_window as f64)) as usize;
-                self.congestion_window += (1472.0 * (acked_packet.size as f64 / self.congestion_window as f64)) as usize;
+        // Do not grow the window when the sender was not using it.
+        if acked_packet.is_app_limited { return; }
+        if self.rtt.smoothed_RTT() != 0 && self.rtt.min_RTT() != 0 {
+            debug!("Estimated bandwidth: {}, estimated current throughput: {}",self.estimate_bandWidth(), self.congestion_window as u64 * 100000000 / self.rtt.smoothed_RTT());
+            if (self.congestion_window as u64 * 100000000 / self.rtt.smoothed_RTT()) as f64 <= self.estimate_bandWidth() as f64 * 0.8 {
+                self.congestion_window += (self.config.max_datagram_size as f64 * (acked_packet.size as f64 / self.congestion_window as f64)) as usize;
+                self.congestion_window += (self.config.max_datagram_size as f64 * (acked_packet.size as f64 / self.congestion_window as f64)) as usize;
             }
         }
         if self.congestion_window < self.slow_start_threshold {
@@ -725,35 +1010,57 @@ impl State {
             self.congestion_window += acked_packet.size;
             debug!("In slow start, increased congestion window to {}", self.congestion_window);
         } else {
-            self.congestion_window += (1472.0 * (acked_packet.size as f64 / self.congestion_window as f64)) as usize;
+            self.congestion_window += (self.config.max_datagram_size as f64 * (acked_packet.size as f64 / self.congestion_window as f64)) as usize;
             debug!("In AIMD, increased congestion window to {}", self.congestion_window);
         }
         if self.congestion_window > self.max_congestion_window { self.max_congestion_window = self.congestion_window; }
     }
-    pub fn cc_on_packet_lost(&mut self, lost_packet: &SentPacket) {
+    /// Record whether the sender is application- or flow-control-limited: the congestion window has room for
+    /// another full packet, but there is no data left to send or the caller is holding new data back.
+    pub fn cc_update_app_limited(&mut self, holding_back: bool) {
+        let out_of_data = !self.has_new_data() && !self.has_lost_data() && !self.init_pending;
+        self.app_limited = self.bytes_in_flight + self.config.max_datagram_size <= self.congestion_window && (holding_back || out_of_data);
+    }
+    /// Congestion window validation, reference from RFC 7661 and RFC 2861. The window is halved for every RTO
+    /// the sender has been idle, down to the initial window, and pacing restarts from a burst of that size.
+    /// Idle time counts once: ACKs and probes sent while idle do not decay the window again for the same RTO.
+    pub fn cc_validate_after_idle(&mut self, now: Instant) {
+        if self.time_of_last_sent_data.is_none() || self.rtt.smoothed_RTT() == 0 { return; }
+        let RTO = self.get_PTO();
+        let idle_since = self.time_of_last_sent_data.into_iter().chain(self.time_of_last_idle_decay).max().unwrap();
+        let mut idle_periods = (now - idle_since).as_nanos() as u64 / RTO;
+        if idle_periods == 0 { return; }
+        self.time_of_last_idle_decay = Some(idle_since + Duration::from_nanos(idle_periods * RTO));
+        let restart_window = cmp::min(self.congestion_window, self.config.initial_window);
+        self.slow_start_threshold = cmp::max(self.slow_start_threshold, self.congestion_window / 4 * 3);
+        while idle_periods > 0 && self.congestion_window > restart_window {
+            self.congestion_window = cmp::max(self.congestion_window / 2, restart_window);
+            idle_periods -= 1;
+        }
+        self.pacing_tokens = restart_window;
+        self.pacing_last_refill = Some(now);
+        debug!("Idle for more than one RTO, congestion window decayed to {}", self.congestion_window);
+    }
+    /// Refill the pacing budget at 5/4 of the congestion window per smoothed RTT, holding at most one window.
+    /// Without an RTT sample there is no rate to pace at, so the budget is kept full.
+    pub fn pacing_refill(&mut self, now: Instant) {
+        if self.rtt.smoothed_RTT() == 0 {
+            self.pacing_tokens = self.congestion_window;
+        } else if self.pacing_last_refill.is_some() {
+            let elapsed = (now - self.pacing_last_refill.unwrap()).as_nanos() as u64;
+            let refill = elapsed as f64 * self.congestion_window as f64 * 5.0 / 4.0 / self.rtt.smoothed_RTT() as f64;
+            self.pacing_tokens = self.pacing_tokens.saturating_add(refill as usize);
+        }
+        self.pacing_tokens = cmp::min(self.pacing_tokens, self.congestion_window);
+        self.pacing_last_refill = Some(now);
+    }
+    pub fn cc_on_packet_lost(&mut self, lost_packet: &SentPacket, now: Instant) {
         self.packet_lost += 1;
         self.bytes_in_flight -= lost_packet.size;
         self.sent_largest_lost = cmp::max(self.sent_largest_lost, lost_packet.packet_num);
-        self.congestion_event(lost_packet.time_sent);
+        self.congestion_event(lost_packet.time_sent, now);
     }
-    pub fn update_RTT(&mut self, mut ack_delay: u64) {
-        if self.max_RTT == 0 {
-            self.min_RTT = self.latest_RTT;
-            self.max_RTT = self.latest_RTT;
-            self.RTT_variance = self.latest_RTT / 2;
-            return;
-        }
-        self.min_RTT = cmp::min(self.min_RTT, self.latest_RTT);
-        // Limit ack_delay by max_ack_delay
-        ack_delay = cmp::min(ack_delay, Duration::from_millis(2).as_nanos() as u64);
-        // Adjust for ack delay if plausible.
-        let adjusted_RTT = if self.latest_RTT > self.min_RTT + ack_delay { self.latest_RTT - ack_delay } else { self.latest_RTT };
-
-        self.RTT_variance = (3.0 / 4.0 * self.RTT_variance as f64 + 1.0 / 4.0 * (self.smoothed_RTT as i64 - adjusted_RTT as i64).abs() as f64) as u64;
-        self.smoothed_RTT = (7.0 / 8.0 * self.smoothed_RTT as f64 + 1.0 / 8.0 * adjusted_RTT as f64) as u64;
-        debug!("Updating RTT. latest RTT: {}, adjusted: {}, smoothed: {}, variance: {}", self.latest_RTT, adjusted_RTT, self.smoothed_RTT, self.RTT_variance);
-    }
-    pub fn detect_packet_lost(&mut self) {
+    pub fn detect_packet_lost(&mut self, now: Instant) {
         let lost_timeout = self.get_lost_timeout();
         let PTO = self.get_PTO();
         let mut PTO_triggered = false;
@@ -763,78 +1070,115 @@ impl State {
             // if sent_packet.is_ack_only { continue; }
             if packet_num < &self.sent_largest_ACKed {
                 // Less than largest ACKed, Time / Reorder threshold
-                if !sent_packet.is_ack_only && sent_packet.time_sent.elapsed().as_nanos() as u64 > lost_timeout || *packet_num < self.sent_largest_ACKed - 3 {
+                if !sent_packet.is_ack_only && (now - sent_packet.time_sent).as_nanos() as u64 > lost_timeout || *packet_num + 3 < self.sent_largest_ACKed {
                     lost.push(packet_num.clone());
                 }
             } else {
                 // larger than largest ACKed, PTO timeout
-                if sent_packet.time_sent.elapsed().as_nanos() as u64 > PTO {
+                if (now - sent_packet.time_sent).as_nanos() as u64 > PTO {
                     PTO_triggered = true;
                     if self.established == false { lost.push(packet_num.clone()); }
                 }
             }
         }
+        // HashMap iteration order is random, handle the losses oldest first so runs are reproducible.
+        lost.sort();
         for key in lost.iter() {
             let sent_packet = self.sent_packets.remove(key).unwrap();
             if self.sent_largest_lost < *key { self.sent_largest_lost = *key; }
             lost_packets.push(sent_packet);
         }
         if lost_packets.len() > 0 {
-            self.on_packets_lost(lost_packets);
+            self.on_packets_lost(lost_packets, now);
         }
         if PTO_triggered {
             if self.PTO_amount > 0 {
-                if self.last_PTO_time.unwrap().elapsed().as_nanos() as u64 > PTO { self.on_PTO(PTO); }
-            } else { self.on_PTO(PTO); }
+                if (now - self.last_PTO_time.unwrap()).as_nanos() as u64 > PTO { self.on_PTO(PTO, now); }
+            } else { self.on_PTO(PTO, now); }
+        }
+    }
+    /// The earliest time one of the timers is due: delayed ACK, loss detection, PTO or pacing.
+    /// The caller can sleep until then unless a packet arrives first. None if no timer is armed.
+    pub fn next_timeout(&self) -> Option<Instant> {
+        if self.PTO_probes_pending > 0 { return self.last_PTO_time; }
+        let mut timeouts = Vec::new();
+        if self.received_largest != 0 && self.connected {
+            for (_, received) in self.received_packets.range(self.ack_starting_packet_num..) {
+                match received {
+                    received if !received.ack_sent => {
+                        let mut ack_delay = self.config.max_ack_delay;
+                        if received.is_ack_only { ack_delay = cmp::max(ack_delay, Duration::from_nanos(self.rtt.smoothed_RTT())); }
+                        timeouts.push(received.time_received.checked_add(ack_delay));
+                    }
+                    _ => {}
+                }
+            }
         }
+        let lost_timeout = Duration::from_nanos(self.get_lost_timeout());
+        let PTO = Duration::from_nanos(self.get_PTO());
+        for (packet_num, sent_packet) in self.sent_packets.iter() {
+            if *packet_num < self.sent_largest_ACKed {
+                if !sent_packet.is_ack_only { timeouts.push(sent_packet.time_sent.checked_add(lost_timeout)); }
+            } else if self.PTO_amount > 0 {
+                timeouts.push(cmp::max(sent_packet.time_sent, self.last_PTO_time.unwrap()).checked_add(PTO));
+            } else {
+                timeouts.push(sent_packet.time_sent.checked_add(PTO));
+            }
+        }
+        // Waiting for the pacing budget to refill, a full congestion window waits for an ACK instead.
+        let has_data = self.has_lost_data() || self.init_pending || (self.established && self.has_new_data());
+        if has_data && self.closing.is_none() && self.bytes_in_flight + self.config.max_datagram_size <= self.congestion_window && self.pacing_tokens < self.config.max_datagram_size && self.rtt.smoothed_RTT() != 0 {
+            let refill_time = (self.config.max_datagram_size - self.pacing_tokens) as f64 * self.rtt.smoothed_RTT() as f64 * 4.0 / 5.0 / self.congestion_window as f64;
+            timeouts.push(self.pacing_last_refill.and_then(|last_refill| last_refill.checked_add(Duration::from_nanos(refill_time as u64 + 1))));
+        }
+        timeouts.into_iter().flatten().min()
     }
-    pub fn on_PTO(&mut self, PTO: u64) {
+    pub fn on_PTO(&mut self, PTO: u64, now: Instant) {
         debug!("PTO of {} triggered. PTO amount: {}", PTO, self.PTO_amount);
         self.last_PTO = PTO;
-        self.last_PTO_time = Some(Instant::now());
+        self.last_PTO_time = Some(now);
         self.PTO_amount += 1;
         if self.PTO_amount == 4 {
-            self.congestion_window = 14720;
+            self.congestion_window = self.config.initial_window;
+        }
+        if self.established && self.closing.is_none() {
+            self.PTO_probes_pending = 2;
+            self.PTO_probed.clear();
         }
-        if self.established == true { self.send_PTO(); self.send_PTO(); }
     }
-    pub fn on_packets_lost(&mut self, lost_packets: Vec<SentPacket>) {
+    pub fn on_packets_lost(&mut self, lost_packets: Vec<SentPacket>, now: Instant) {
         for lost_packet in lost_packets.iter() {
             debug!("Packet {:?} declared as lost.", lost_packet);
-            self.cc_on_packet_lost(lost_packet);
-            self.lost_packets.push_back(lost_packet.packet_num);
-        }
-    }
-    pub fn resend_lost_packet_data(&mut self, data: &Vec<u8>) {
-        while self.bytes_in_flight < self.congestion_window {
-            if self.lost_packets.len() == 0 { return; }
-            let lost_packet_num = self.lost_packets.pop_front().unwrap();
-            let data_segment = self.send_state.sent_data.remove(&lost_packet_num);
-            if data_segment != None {
-                let data_segment = data_segment.unwrap();
-                self.build_new_data_packet_from_segment(data, data_segment);
+            self.cc_on_packet_lost(lost_packet, now);
+            let data_segments = match self.send_state.sent_data.remove(&lost_packet.packet_num) {
+                Some(data_segments) => data_segments,
+                None => continue,
+            };
+            if data_segments.iter().any(|data_segment| data_segment.length == 0) { self.send_state.lost_empty_packet = true; }
+            for (start, end) in self.unacked_ranges(&data_segments).iter() {
+                self.send_state.lost_data.insert(start, end);
             }
-            if !self.send_a_packet_in_queue() { break; }
         }
     }
+    /// Free the start of the send buffer that no packet in flight carries and that is not waiting to be sent again.
+    pub fn release_acked_data(&mut self) {
+        let in_flight_from = self.send_state.sent_data.values().flatten().map(|data_segment| data_segment.byte_offset).min();
+        let lost_from = self.send_state.lost_data.first().map(|(start, _)| start);
+        let needed_from = in_flight_from.into_iter().chain(lost_from).min().unwrap_or(self.next_byte_offset as u64);
+        self.send_state.buffer.release(needed_from);
+        self.send_state.acked_data.remove(0, self.send_state.buffer.start());
+    }
     pub fn generate_close_frame(&self) -> Frame {
         Frame {
             frame_type: FrameType::CLOSE,
             frame_data: vec![0],
         }
     }
-    pub fn send_close_packet(&mut self) {
-        let mut packet = self.build_new_empty_packet();
-        packet.frames.push(self.generate_close_frame());
-        self.closing = Some(packet.header.packet_num);
-        debug!("Sending Close packet.");
-        self.send_packet(packet);
-    }
     pub fn estimate_bandWidth(&self) -> u64 {
-        if self.min_RTT == 0 {
-            14720 * 100000000 / Duration::from_millis(100).as_nanos() as u64
+        if self.rtt.min_RTT() == 0 {
+            self.config.initial_window as u64 * 100000000 / Duration::from_millis(100).as_nanos() as u64
         } else {
-            self.max_congestion_window as u64 * 100000000 / self.min_RTT / 2
+            self.max_congestion_window as u64 * 100000000 / self.rtt.min_RTT() / 2
         }
     }
 }
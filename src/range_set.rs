@@ -0,0 +1,58 @@
+//! A set of byte ranges of the stream, kept merged: the sender tracks which bytes were acknowledged and which
+//! were lost and still have to be sent again with it, whatever packets they were first sent in.
+
+use std::collections::BTreeMap;
+
+#[derive(PartialEq, Clone, Debug, Default)]
+pub struct RangeSet {
+    /// End offset keyed by start offset. Ranges are never empty and never overlap or touch.
+    ranges: BTreeMap<u64, u64>,
+}
+
+impl RangeSet {
+    pub fn new() -> RangeSet {
+        RangeSet::default()
+    }
+    /// Add the bytes from `start` to `end`, merging with the ranges they overlap or touch.
+    pub fn insert(&mut self, start: u64, end: u64) {
+        if start >= end { return; }
+        let mut merged = (start, end);
+        let touching: Vec<(u64, u64)> = self.ranges.range(..=end).rev()
+            .take_while(|(_, &range_end)| range_end >= start)
+            .map(|(&range_start, &range_end)| (range_start, range_end))
+            .collect();
+        for (range_start, range_end) in touching {
+            self.ranges.remove(&range_start);
+            merged = (merged.0.min(range_start), merged.1.max(range_end));
+        }
+        self.ranges.insert(merged.0, merged.1);
+    }
+    /// Take out the bytes from `start` to `end`, splitting the ranges they cut through.
+    pub fn remove(&mut self, start: u64, end: u64) {
+        if start >= end { return; }
+        let overlapping: Vec<(u64, u64)> = self.ranges.range(..end).rev()
+            .take_while(|(_, &range_end)| range_end > start)
+            .map(|(&range_start, &range_end)| (range_start, range_end))
+            .collect();
+        for (range_start, range_end) in overlapping {
+            self.ranges.remove(&range_start);
+            if range_start < start { self.ranges.insert(range_start, start); }
+            if range_end > end { self.ranges.insert(end, range_end); }
+        }
+    }
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+    /// The range with the lowest offset.
+    pub fn first(&self) -> Option<(u64, u64)> {
+        self.ranges.iter().next().map(|(&start, &end)| (start, end))
+    }
+    /// The ranges as start and end offsets, lowest first.
+    pub fn iter(&self) -> impl Iterator<Item = (u64, u64)> + '_ {
+        self.ranges.iter().map(|(&start, &end)| (start, end))
+    }
+    /// Total bytes in the set.
+    pub fn bytes(&self) -> u64 {
+        self.ranges.iter().map(|(start, end)| end - start).sum()
+    }
+}
@@ -0,0 +1,110 @@
+//! Reassembly of the received byte stream. Segments can arrive in any order, more than once, and split at
+//! different offsets when a lost packet is re-packaged, so they are kept as a set of byte ranges: overlaps
+//! are trimmed, adjacent ranges merged, and bytes that disagree with what was already received are reported.
+
+use std::cmp;
+use std::collections::BTreeMap;
+
+/// A segment carried different bytes than were already received at `offset`.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub struct Conflict {
+    pub offset: u64,
+}
+
+#[derive(PartialEq, Clone, Debug, Default)]
+pub struct ReassemblyBuffer {
+    /// Bytes before this offset have been handed out by `read`.
+    read_offset: u64,
+    /// Received bytes past `read_offset`, keyed by start offset. Ranges never overlap or touch.
+    ranges: BTreeMap<u64, Vec<u8>>,
+}
+
+impl ReassemblyBuffer {
+    pub fn new() -> ReassemblyBuffer {
+        ReassemblyBuffer::default()
+    }
+    /// Add the bytes of a segment starting at `offset`. Bytes that were already read out are dropped
+    /// without being compared, and so is a segment reaching past the largest offset, which no stream has.
+    /// On a conflict nothing is added. Returns how many bytes were new.
+    pub fn insert(&mut self, offset: u64, data: &[u8]) -> Result<usize, Conflict> {
+        let end = match offset.checked_add(data.len() as u64) {
+            Some(end) => end,
+            None => return Ok(0),
+        };
+        if end <= self.read_offset { return Ok(0); }
+        let (offset, data) = if offset < self.read_offset {
+            (self.read_offset, &data[(self.read_offset - offset) as usize..])
+        } else {
+            (offset, data)
+        };
+        if data.is_empty() { return Ok(0); }
+
+        // The ranges overlapping or touching the segment, in order.
+        let mut touching = Vec::new();
+        for (&start, bytes) in self.ranges.range(..=end).rev() {
+            if start + (bytes.len() as u64) < offset { break; }
+            touching.push(start);
+        }
+        touching.reverse();
+        for start in touching.iter() {
+            let bytes = &self.ranges[start];
+            let overlap_start = cmp::max(*start, offset);
+            let overlap_end = cmp::min(*start + bytes.len() as u64, end);
+            for position in overlap_start..overlap_end {
+                if bytes[(position - start) as usize] != data[(position - offset) as usize] {
+                    return Err(Conflict { offset: position });
+                }
+            }
+        }
+
+        // Extend the range that starts at or before the segment in place, so in-order data is only copied once.
+        let mut existing = 0;
+        let (merged_start, mut merged) = match touching.first() {
+            Some(&start) if start <= offset => {
+                let bytes = self.ranges.remove(&start).unwrap();
+                existing += bytes.len();
+                (start, bytes)
+            }
+            _ => (offset, Vec::with_capacity(data.len())),
+        };
+        let merged_end = merged_start + merged.len() as u64;
+        if merged_end < end {
+            merged.extend_from_slice(&data[(merged_end - offset) as usize..]);
+        }
+        for start in touching.iter() {
+            if let Some(bytes) = self.ranges.remove(start) {
+                existing += bytes.len();
+                let merged_end = merged_start + merged.len() as u64;
+                let bytes_end = start + bytes.len() as u64;
+                if bytes_end > merged_end {
+                    merged.extend_from_slice(&bytes[(merged_end - start) as usize..]);
+                }
+            }
+        }
+        let new_bytes = merged.len() - existing;
+        self.ranges.insert(merged_start, merged);
+        Ok(new_bytes)
+    }
+    /// Take the bytes that continue the stream from where the last read stopped, if any have arrived.
+    pub fn read(&mut self) -> Option<Vec<u8>> {
+        let bytes = self.ranges.remove(&self.read_offset)?;
+        self.read_offset += bytes.len() as u64;
+        Some(bytes)
+    }
+    /// End of the bytes received without a gap from the start of the stream, read or not.
+    pub fn contiguous_end(&self) -> u64 {
+        self.read_offset + self.ranges.get(&self.read_offset).map_or(0, |bytes| bytes.len() as u64)
+    }
+    /// Offset of the first byte not read yet.
+    pub fn read_offset(&self) -> u64 {
+        self.read_offset
+    }
+    /// Bytes received but not read yet, including the ones waiting for a gap to be filled.
+    pub fn buffered(&self) -> usize {
+        self.ranges.values().map(|bytes| bytes.len()).sum()
+    }
+    /// The received ranges not read yet, as start and end offsets.
+    pub fn ranges(&self) -> Vec<(u64, u64)> {
+        self.ranges.iter().map(|(&start, bytes)| (start, start + bytes.len() as u64)).collect()
+    }
+}
@@ -0,0 +1,131 @@
+#![allow(non_snake_case)]
+
+use std::cmp;
+use std::time::{Duration, Instant};
+
+#[derive(PartialEq, Clone, Copy, Debug)]
+struct Sample {
+    time: Instant,
+    value: u64,
+}
+
+/// Kathleen Nichols' windowed min/max filter, reference from Linux lib/win_minmax.c.
+/// Keeps the best, second best and third best samples of the window so the estimate
+/// expires gracefully instead of being held forever.
+#[derive(PartialEq, Clone, Debug)]
+pub struct WindowedFilter {
+    window: Duration,
+    is_max: bool,
+    samples: Option<[Sample; 3]>,
+}
+
+impl WindowedFilter {
+    pub fn new_min(window: Duration) -> WindowedFilter {
+        WindowedFilter { window, is_max: false, samples: None }
+    }
+    pub fn new_max(window: Duration) -> WindowedFilter {
+        WindowedFilter { window, is_max: true, samples: None }
+    }
+    pub fn get(&self) -> u64 {
+        match self.samples {
+            Some(samples) => samples[0].value,
+            None => 0,
+        }
+    }
+    fn is_better(&self, value: u64, than: u64) -> bool {
+        if self.is_max { value >= than } else { value <= than }
+    }
+    pub fn reset(&mut self, value: u64, now: Instant) {
+        let sample = Sample { time: now, value };
+        self.samples = Some([sample; 3]);
+    }
+    pub fn update(&mut self, value: u64, now: Instant) -> u64 {
+        let mut samples = match self.samples {
+            Some(samples) => samples,
+            None => { self.reset(value, now); return value; }
+        };
+        let sample = Sample { time: now, value };
+        if self.is_better(value, samples[0].value) || now - samples[2].time > self.window {
+            self.reset(value, now);
+            return value;
+        }
+        if self.is_better(value, samples[1].value) {
+            samples[1] = sample;
+            samples[2] = sample;
+        } else if self.is_better(value, samples[2].value) {
+            samples[2] = sample;
+        }
+        // Age out the best sample, promoting the next best ones of the later sub-windows.
+        let elapsed = now - samples[0].time;
+        if elapsed > self.window {
+            samples[0] = samples[1];
+            samples[1] = samples[2];
+            samples[2] = sample;
+            if now - samples[0].time > self.window {
+                samples[0] = samples[1];
+                samples[1] = samples[2];
+                samples[2] = sample;
+            }
+        } else if samples[1].time == samples[0].time && elapsed > self.window / 4 {
+            samples[1] = sample;
+            samples[2] = sample;
+        } else if samples[2].time == samples[1].time && elapsed > self.window / 2 {
+            samples[2] = sample;
+        }
+        self.samples = Some(samples);
+        samples[0].value
+    }
+}
+
+/// RTT estimation, reference from QUIC RFC. https://quicwg.org/base-drafts/draft-ietf-quic-recovery.html
+/// All values are in nanoseconds, 0 until the first sample is taken.
+#[derive(PartialEq, Clone, Debug)]
+pub struct RttEstimator {
+    latest_RTT: u64,
+    smoothed_RTT: u64,
+    RTT_variance: u64,
+    min_RTT: WindowedFilter,
+    max_RTT: WindowedFilter,
+    max_ack_delay: u64,
+}
+
+impl RttEstimator {
+    /// ACK delays reported by the peer are taken off RTT samples up to `max_ack_delay`.
+    pub fn new(max_ack_delay: Duration) -> RttEstimator {
+        RttEstimator {
+            latest_RTT: 0,
+            smoothed_RTT: 0,
+            RTT_variance: 0,
+            min_RTT: WindowedFilter::new_min(Duration::from_secs(10)),
+            max_RTT: WindowedFilter::new_max(Duration::from_secs(10)),
+            max_ack_delay: max_ack_delay.as_nanos() as u64,
+        }
+    }
+    pub fn latest_RTT(&self) -> u64 { self.latest_RTT }
+    pub fn smoothed_RTT(&self) -> u64 { self.smoothed_RTT }
+    pub fn RTT_variance(&self) -> u64 { self.RTT_variance }
+    /// Smallest RTT sample of the last 10 seconds.
+    pub fn min_RTT(&self) -> u64 { self.min_RTT.get() }
+    /// Largest RTT sample of the last 10 seconds.
+    pub fn max_RTT(&self) -> u64 { self.max_RTT.get() }
+    pub fn has_sample(&self) -> bool { self.latest_RTT != 0 }
+    pub fn update(&mut self, latest_RTT: u64, mut ack_delay: u64, now: Instant) {
+        let first_sample = !self.has_sample();
+        self.latest_RTT = latest_RTT;
+        let min_RTT = self.min_RTT.update(latest_RTT, now);
+        self.max_RTT.update(latest_RTT, now);
+        // The first sample only sets the variance, the smoothed RTT is taken from the second sample on.
+        if first_sample {
+            self.RTT_variance = latest_RTT / 2;
+            return;
+        }
+        // Limit ack_delay by max_ack_delay
+        ack_delay = cmp::min(ack_delay, self.max_ack_delay);
+        // Adjust for ack delay if plausible.
+        let adjusted_RTT = if latest_RTT > min_RTT + ack_delay { latest_RTT - ack_delay } else { latest_RTT };
+
+        self.RTT_variance = (3.0 / 4.0 * self.RTT_variance as f64 + 1.0 / 4.0 * (self.smoothed_RTT as i64 - adjusted_RTT as i64).abs() as f64) as u64;
+        self.smoothed_RTT = (7.0 / 8.0 * self.smoothed_RTT as f64 + 1.0 / 8.0 * adjusted_RTT as f64) as u64;
+        debug!("Updating RTT. latest RTT: {}, adjusted: {}, smoothed: {}, variance: {}, min: {}", latest_RTT, adjusted_RTT, self.smoothed_RTT, self.RTT_variance, min_RTT);
+    }
+}
@@ -0,0 +1,67 @@
+//! The bytes of the stream the sender holds: written by the application, sent, and kept until every packet
+//! carrying them is acknowledged, in case they have to be sent again. The buffer is bounded, so the input is
+//! read only as fast as the network delivers it and never has to fit in memory as a whole.
+
+use std::collections::VecDeque;
+
+/// Large enough not to limit the congestion window on a fast path.
+pub const DEFAULT_CAPACITY: usize = 4 * 1024 * 1024;
+
+#[derive(PartialEq, Clone, Debug)]
+pub struct SendBuffer {
+    /// Stream offset of the first byte held.
+    start: u64,
+    data: VecDeque<u8>,
+    capacity: usize,
+    /// The application will not write anything after `end`.
+    finished: bool,
+}
+
+impl SendBuffer {
+    pub fn new(capacity: usize) -> SendBuffer {
+        SendBuffer { start: 0, data: VecDeque::new(), capacity, finished: false }
+    }
+    /// Append as much of `bytes` as there is room for, returns how many were taken.
+    pub fn write(&mut self, bytes: &[u8]) -> usize {
+        if self.finished { return 0; }
+        let length = bytes.len().min(self.free_space());
+        self.data.extend(&bytes[..length]);
+        length
+    }
+    /// Mark the end of the stream, nothing can be written after it.
+    pub fn finish(&mut self) {
+        self.finished = true;
+    }
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+    pub fn free_space(&self) -> usize {
+        self.capacity - self.data.len()
+    }
+    /// Stream offset of the first byte still held.
+    pub fn start(&self) -> u64 {
+        self.start
+    }
+    /// Stream offset just past the last byte written.
+    pub fn end(&self) -> u64 {
+        self.start + self.data.len() as u64
+    }
+    /// Copy up to `length` bytes from `offset`, fewer if the stream does not reach that far yet.
+    pub fn get(&self, offset: u64, length: usize) -> Vec<u8> {
+        assert!(offset >= self.start, "byte {} was already released", offset);
+        let from = ((offset - self.start) as usize).min(self.data.len());
+        let to = (from + length).min(self.data.len());
+        let (first, second) = self.data.as_slices();
+        let mut output = Vec::with_capacity(to - from);
+        if from < first.len() { output.extend_from_slice(&first[from..to.min(first.len())]); }
+        if to > first.len() { output.extend_from_slice(&second[from.max(first.len()) - first.len()..to - first.len()]); }
+        output
+    }
+    /// Drop the bytes before `offset`, they will not be needed again.
+    pub fn release(&mut self, offset: u64) {
+        if offset <= self.start { return; }
+        let length = ((offset - self.start) as usize).min(self.data.len());
+        self.data.drain(..length);
+        self.start += length as u64;
+    }
+}
@@ -0,0 +1,408 @@
+//! Deterministic in-process network simulator. A sender and a receiver `State` are connected through a pair
+//! of virtual links modelled after the course netsim, with every random decision taken from a seeded RNG and
+//! time taken from a `MockClock`. The same seed always gives the same run, and a transfer that takes seconds
+//! of virtual time finishes in milliseconds.
+
+use crate::clock::{Clock, MockClock};
+use crate::config::ConnectionConfig;
+use crate::protocol::State;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::cmp;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Impairments of one direction of the link. Percentages are 0 to 100, like the netsim flags.
+#[derive(PartialEq, Clone, Debug)]
+pub struct LinkConfig {
+    /// Link rate in Mb/s.
+    pub bandwidth: f64,
+    /// One-way propagation delay.
+    pub latency: Duration,
+    /// The delay of each datagram varies uniformly by up to this much either way.
+    pub jitter: Duration,
+    pub drop: f64,
+    pub duplicate: f64,
+    /// Datagrams held back for up to one latency, so the ones sent after them overtake them.
+    pub reorder: f64,
+    /// Datagrams held back for an extra `delay_amount`.
+    pub delay: f64,
+    pub delay_amount: Duration,
+    /// Chance of a loss burst starting, dropping `burst_length` datagrams in a row.
+    pub burst: f64,
+    pub burst_length: u32,
+    /// Bytes that can wait for the link before datagrams are tail dropped, unlimited if None.
+    pub queue_limit: Option<usize>,
+}
+
+impl Default for LinkConfig {
+    /// The netsim defaults: 1 Mb/s, 10 ms latency and no impairments.
+    fn default() -> LinkConfig {
+        LinkConfig {
+            bandwidth: 1.0,
+            latency: Duration::from_millis(10),
+            jitter: Duration::from_millis(0),
+            drop: 0.0,
+            duplicate: 0.0,
+            reorder: 0.0,
+            delay: 0.0,
+            delay_amount: Duration::from_millis(100),
+            burst: 0.0,
+            burst_length: 0,
+            queue_limit: None,
+        }
+    }
+}
+
+#[derive(PartialEq, Clone, Debug, Default)]
+pub struct LinkStats {
+    /// Datagrams and bytes handed to the link by the endpoint.
+    pub datagrams_sent: u64,
+    pub bytes_sent: u64,
+    /// Datagrams and bytes that went over the wire, duplicates included.
+    pub datagrams_on_wire: u64,
+    pub bytes_on_wire: u64,
+    pub dropped: u64,
+    pub duplicated: u64,
+    pub reordered: u64,
+    pub delayed: u64,
+    pub delivered: u64,
+}
+
+#[derive(Debug)]
+struct InFlight {
+    arrival: Instant,
+    seq: u64,
+    datagram: Vec<u8>,
+}
+
+/// One direction of the virtual link.
+#[derive(Debug)]
+pub struct Link {
+    pub config: LinkConfig,
+    pub stats: LinkStats,
+    busy_until: Instant,
+    in_flight: Vec<InFlight>,
+    next_seq: u64,
+    burst_remaining: u32,
+}
+
+impl Link {
+    pub fn new(config: LinkConfig, now: Instant) -> Link {
+        Link { config, stats: LinkStats::default(), busy_until: now, in_flight: Vec::new(), next_seq: 0, burst_remaining: 0 }
+    }
+    fn transmission_time(&self, size: usize) -> Duration {
+        Duration::from_secs_f64(size as f64 * 8.0 / (self.config.bandwidth * 1_000_000.0))
+    }
+    /// Put a datagram on the link, deciding its fate with `rng`. Returns when each copy of it will arrive,
+    /// nothing if it was dropped.
+    pub fn send(&mut self, datagram: Vec<u8>, now: Instant, rng: &mut StdRng) -> Vec<Instant> {
+        self.stats.datagrams_sent += 1;
+        self.stats.bytes_sent += datagram.len() as u64;
+        let mut arrivals = Vec::new();
+        if self.burst_remaining == 0 && self.config.burst > 0.0 && rng.gen_bool(self.config.burst / 100.0) {
+            self.burst_remaining = self.config.burst_length;
+        }
+        if self.burst_remaining > 0 {
+            debug!("[sim] Dropping datagram of size {} in a loss burst.", datagram.len());
+            self.burst_remaining -= 1;
+            self.stats.dropped += 1;
+            return arrivals;
+        }
+        if rng.gen_bool(self.config.drop / 100.0) {
+            debug!("[sim] Dropping datagram of size {}.", datagram.len());
+            self.stats.dropped += 1;
+            return arrivals;
+        }
+        let copies = if rng.gen_bool(self.config.duplicate / 100.0) { self.stats.duplicated += 1; 2 } else { 1 };
+        for _ in 0..copies {
+            let backlog = self.busy_until.saturating_duration_since(now).as_secs_f64() * self.config.bandwidth * 1_000_000.0 / 8.0;
+            if let Some(queue_limit) = self.config.queue_limit {
+                if backlog as usize + datagram.len() > queue_limit {
+                    debug!("[sim] Queue full, dropping datagram of size {}.", datagram.len());
+                    self.stats.dropped += 1;
+                    continue;
+                }
+            }
+            self.busy_until = cmp::max(self.busy_until, now) + self.transmission_time(datagram.len());
+            self.stats.datagrams_on_wire += 1;
+            self.stats.bytes_on_wire += datagram.len() as u64;
+            let mut arrival = self.busy_until + self.config.latency;
+            if self.config.jitter > Duration::from_millis(0) {
+                let jitter = self.config.jitter.as_nanos() as i64;
+                let offset = rng.gen_range(-jitter, jitter + 1);
+                arrival = if offset < 0 {
+                    cmp::max(self.busy_until, arrival - Duration::from_nanos(-offset as u64))
+                } else {
+                    arrival + Duration::from_nanos(offset as u64)
+                };
+            }
+            if rng.gen_bool(self.config.reorder / 100.0) {
+                self.stats.reordered += 1;
+                arrival += Duration::from_nanos(rng.gen_range(0, self.config.latency.as_nanos() as u64 + 1));
+            }
+            if rng.gen_bool(self.config.delay / 100.0) {
+                self.stats.delayed += 1;
+                arrival += self.config.delay_amount;
+            }
+            self.in_flight.push(InFlight { arrival, seq: self.next_seq, datagram: datagram.clone() });
+            self.next_seq += 1;
+            arrivals.push(arrival);
+        }
+        arrivals
+    }
+    /// When the next datagram arrives at the other end.
+    pub fn next_arrival(&self) -> Option<Instant> {
+        self.in_flight.iter().map(|in_flight| in_flight.arrival).min()
+    }
+    /// Take every datagram that has arrived by `now`, in arrival order.
+    pub fn deliver(&mut self, now: Instant) -> Vec<Vec<u8>> {
+        let (mut arrived, in_flight): (Vec<InFlight>, Vec<InFlight>) = self.in_flight.drain(..).partition(|in_flight| in_flight.arrival <= now);
+        self.in_flight = in_flight;
+        arrived.sort_by_key(|in_flight| (in_flight.arrival, in_flight.seq));
+        self.stats.delivered += arrived.len() as u64;
+        arrived.into_iter().map(|in_flight| in_flight.datagram).collect()
+    }
+}
+
+#[derive(PartialEq, Clone, Copy, Debug)]
+enum Phase {
+    Handshake,
+    Transfer,
+    Closing { attempts: u32, since: Instant },
+    Done,
+}
+
+fn close_timeout(state: &State) -> Duration {
+    cmp::max(2 * Duration::from_nanos(state.rtt.smoothed_RTT()), Duration::from_millis(100))
+}
+
+/// Retry CLOSE until the peer answers or `max_attempts` have been sent.
+fn step_closing(state: &mut State, attempts: u32, since: Instant, max_attempts: u32, now: Instant) -> Phase {
+    if !state.connected || attempts >= max_attempts { return Phase::Done; }
+    if now - since >= close_timeout(state) {
+        state.close();
+        return Phase::Closing { attempts: attempts + 1, since: now };
+    }
+    Phase::Closing { attempts, since }
+}
+
+fn next_timeout(state: &State, phase: Phase) -> Option<Instant> {
+    match phase {
+        Phase::Handshake | Phase::Transfer => state.next_timeout(),
+        Phase::Closing { since, .. } => Some(since + close_timeout(state)),
+        Phase::Done => None,
+    }
+}
+
+/// The sending end, following the steps of the `3700send` main loop.
+#[derive(Debug)]
+pub struct Sender {
+    pub state: State,
+    data: Vec<u8>,
+    /// How much of `data` was written into the send buffer so far.
+    written: usize,
+    phase: Phase,
+}
+
+impl Sender {
+    pub fn new(state: State, data: Vec<u8>) -> Sender {
+        Sender { state, data, written: 0, phase: Phase::Handshake }
+    }
+    /// Write as much of the data as the send buffer takes, like `3700send` does with its input.
+    fn write_data(&mut self) {
+        if self.state.send_state.buffer.is_finished() { return; }
+        self.written += self.state.write_data(&self.data[self.written..]);
+        if self.written == self.data.len() { self.state.finish_data(); }
+    }
+    /// Start the handshake, the INIT goes out with the next transmit.
+    pub fn start(&mut self) {
+        self.write_data();
+        self.state.connect();
+    }
+    pub fn handle_datagram(&mut self, datagram: &[u8], from: SocketAddr, now: Instant) {
+        if self.phase != Phase::Done { self.state.handle_datagram(datagram, from, now); }
+    }
+    /// One iteration of the main loop, after the received datagrams are handled.
+    pub fn step(&mut self, now: Instant) {
+        self.write_data();
+        let state = &mut self.state;
+        self.phase = match self.phase {
+            Phase::Handshake => {
+                state.handle_timeout(now);
+                if state.established { Phase::Transfer } else { Phase::Handshake }
+            }
+            Phase::Transfer => {
+                state.handle_timeout(now);
+                if state.closing.is_none() { Phase::Transfer } else { Phase::Closing { attempts: 0, since: now } }
+            }
+            Phase::Closing { attempts, since } => step_closing(state, attempts, since, 3, now),
+            Phase::Done => Phase::Done,
+        };
+    }
+    pub fn next_timeout(&self) -> Option<Instant> {
+        next_timeout(&self.state, self.phase)
+    }
+    pub fn is_done(&self) -> bool {
+        self.phase == Phase::Done
+    }
+}
+
+/// The receiving end, following the steps of the `3700recv` main loop.
+#[derive(Debug)]
+pub struct Receiver {
+    pub state: State,
+    phase: Phase,
+    /// What was read out of the state so far.
+    output: Vec<u8>,
+    /// What was written out once the transfer completed, and when.
+    pub delivered: Option<Vec<u8>>,
+    pub completed_at: Option<Instant>,
+}
+
+impl Receiver {
+    pub fn new(state: State) -> Receiver {
+        Receiver { state, phase: Phase::Handshake, output: Vec::new(), delivered: None, completed_at: None }
+    }
+    pub fn handle_datagram(&mut self, datagram: &[u8], from: SocketAddr, now: Instant) {
+        if self.phase != Phase::Done { self.state.handle_datagram(datagram, from, now); }
+    }
+    /// One iteration of the main loop, after the received datagrams are handled.
+    pub fn step(&mut self, now: Instant) {
+        let state = &mut self.state;
+        while let Some(data) = state.read_data() { self.output.extend(data); }
+        self.phase = match self.phase {
+            Phase::Handshake => {
+                state.handle_timeout(now);
+                if state.established { Phase::Transfer } else { Phase::Handshake }
+            }
+            Phase::Transfer => {
+                state.handle_timeout(now);
+                if state.all_data_received() {
+                    self.delivered = Some(self.output.clone());
+                    self.completed_at = Some(now);
+                    state.close();
+                    Phase::Closing { attempts: 1, since: now }
+                } else {
+                    Phase::Transfer
+                }
+            }
+            Phase::Closing { attempts, since } => step_closing(state, attempts, since, 3, now),
+            Phase::Done => Phase::Done,
+        };
+    }
+    pub fn next_timeout(&self) -> Option<Instant> {
+        next_timeout(&self.state, self.phase)
+    }
+    pub fn is_done(&self) -> bool {
+        self.phase == Phase::Done
+    }
+}
+
+#[derive(PartialEq, Clone, Debug)]
+pub struct SimulationResult {
+    /// What the receiver wrote out, None if the transfer did not complete.
+    pub delivered: Option<Vec<u8>>,
+    /// Virtual time from the start until the receiver had all the data.
+    pub completion_time: Option<Duration>,
+    /// Virtual time until both endpoints were done, or the timeout.
+    pub elapsed: Duration,
+    pub sender_to_receiver: LinkStats,
+    pub receiver_to_sender: LinkStats,
+}
+
+impl SimulationResult {
+    pub fn data_matches(&self, data: &[u8]) -> bool {
+        self.delivered.as_ref().map(|delivered| &delivered[..] == data).unwrap_or(false)
+    }
+    /// Bytes the endpoints sent in both directions, what the course `run` script reports as bytes sent.
+    pub fn bytes_sent(&self) -> u64 {
+        self.sender_to_receiver.bytes_sent + self.receiver_to_sender.bytes_sent
+    }
+}
+
+/// A transfer of data from a `Sender` to a `Receiver` over simulated links.
+pub struct Simulation {
+    pub clock: MockClock,
+    pub sender: Sender,
+    pub receiver: Receiver,
+    pub to_receiver: Link,
+    pub to_sender: Link,
+    rng: StdRng,
+    sender_addr: SocketAddr,
+    receiver_addr: SocketAddr,
+    start: Instant,
+}
+
+impl Simulation {
+    /// Simulate with the same impairments in both directions.
+    pub fn new(data: Vec<u8>, link: LinkConfig, seed: u64) -> Simulation {
+        Simulation::with_links(data, link.clone(), link, seed)
+    }
+    pub fn with_links(data: Vec<u8>, to_receiver: LinkConfig, to_sender: LinkConfig, seed: u64) -> Simulation {
+        let clock = MockClock::new();
+        let start = clock.now();
+        let mut rng = StdRng::seed_from_u64(seed);
+        let sender_addr: SocketAddr = "127.0.0.1:29170".parse().unwrap();
+        let receiver_addr: SocketAddr = "127.0.0.1:28899".parse().unwrap();
+        let sender = State::with_clock(rng.gen_range(1, u8::MAX as u64), Some(receiver_addr), ConnectionConfig::default(), Arc::new(clock.clone()));
+        let receiver = State::with_clock(rng.gen_range(1, u8::MAX as u64), None, ConnectionConfig::default(), Arc::new(clock.clone()));
+        Simulation {
+            sender: Sender::new(sender, data),
+            receiver: Receiver::new(receiver),
+            to_receiver: Link::new(to_receiver, start),
+            to_sender: Link::new(to_sender, start),
+            rng,
+            sender_addr,
+            receiver_addr,
+            start,
+            clock,
+        }
+    }
+    /// Run until both endpoints are done or `timeout` of virtual time has passed.
+    pub fn run(&mut self, timeout: Duration) -> SimulationResult {
+        let deadline = self.start + timeout;
+        self.sender.start();
+        self.flush(self.clock.now());
+        loop {
+            let now = self.clock.now();
+            for datagram in self.to_receiver.deliver(now) {
+                self.receiver.handle_datagram(&datagram, self.sender_addr, now);
+            }
+            for datagram in self.to_sender.deliver(now) {
+                self.sender.handle_datagram(&datagram, self.receiver_addr, now);
+            }
+            self.receiver.step(now);
+            self.sender.step(now);
+            self.flush(now);
+            if (self.sender.is_done() && self.receiver.is_done()) || now >= deadline { break; }
+
+            let timeouts = vec![
+                self.to_receiver.next_arrival(),
+                self.to_sender.next_arrival(),
+                self.sender.next_timeout(),
+                self.receiver.next_timeout(),
+                Some(deadline),
+            ];
+            let next = timeouts.into_iter().flatten().min().unwrap();
+            // A timer that is already due but did nothing would stall virtual time, move on like the event loop does.
+            self.clock.advance_to(if next > now { next } else { now + Duration::from_millis(1) });
+        }
+        SimulationResult {
+            delivered: self.receiver.delivered.clone(),
+            completion_time: self.receiver.completed_at.map(|completed_at| completed_at - self.start),
+            elapsed: self.clock.now() - self.start,
+            sender_to_receiver: self.to_receiver.stats.clone(),
+            receiver_to_sender: self.to_sender.stats.clone(),
+        }
+    }
+    fn flush(&mut self, now: Instant) {
+        while let Some(transmit) = self.sender.state.poll_transmit(now) {
+            self.to_receiver.send(transmit.contents, now, &mut self.rng);
+        }
+        while let Some(transmit) = self.receiver.state.poll_transmit(now) {
+            self.to_sender.send(transmit.contents, now, &mut self.rng);
+        }
+    }
+}
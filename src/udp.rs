@@ -0,0 +1,184 @@
+use crate::batch::Batch;
+use crate::ecn::{self, EcnCodepoint};
+use crate::error::Result;
+use crate::protocol::State;
+use chrono::prelude::*;
+use rand::Rng;
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+
+/// Datagrams a peer can have waiting for room in the socket buffer, those produced beyond it are lost.
+pub const MAX_QUEUED: usize = 1024;
+
+/// Bind to a random port to open a UDP socket. Returns the socket.
+pub fn bind_socket(ip: &str) -> Result<UdpSocket> {
+    let mut rng = rand::thread_rng();
+    let socket = loop {
+        let port = rng.gen_range(29170, 29998);
+        match UdpSocket::bind(format!("{}:{}", ip, port)) {
+            Ok(socket) => break socket,
+            Err(ref e) if e.kind() == io::ErrorKind::AddrInUse => continue,
+            Err(e) => return Err(e.into()),
+        }
+    };
+    eprintln!("{:?} [bound] {}", Local::now(), socket.local_addr()?.port());
+    socket.set_nonblocking(true)?;
+    Ok(socket)
+}
+
+/// Specify port when binding to UDP socket, useful for development.
+pub fn bind_socket_with_port(ip: &str, port: &str) -> Result<UdpSocket> {
+    let socket = UdpSocket::bind(format!("{}:{}", ip, port))?;
+    eprintln!("{:?} [bound] {}", Local::now(), socket.local_addr()?.port());
+    socket.set_nonblocking(true)?;
+    Ok(socket)
+}
+
+/// Bind to `addr`, port 0 picks a free one. An IPv6 socket takes IPv4 peers too, seen as IPv4-mapped addresses,
+/// whatever the system default is.
+pub fn bind_addr(addr: SocketAddr) -> Result<UdpSocket> {
+    let socket = if addr.is_ipv6() { bind_dual_stack(addr)? } else { UdpSocket::bind(addr)? };
+    socket.set_nonblocking(true)?;
+    Ok(socket)
+}
+
+/// IPV6_V6ONLY has to be cleared before binding, which `UdpSocket` does not allow.
+#[cfg(target_os = "linux")]
+fn bind_dual_stack(addr: SocketAddr) -> io::Result<UdpSocket> {
+    use std::mem;
+    use std::os::unix::io::FromRawFd;
+    let fd = unsafe { libc::socket(libc::AF_INET6, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0) };
+    if fd < 0 { return Err(io::Error::last_os_error()); }
+    // Owns the descriptor from here on, and closes it on error.
+    let socket = unsafe { UdpSocket::from_raw_fd(fd) };
+    let v6_only: libc::c_int = 0;
+    if unsafe { libc::setsockopt(fd, libc::IPPROTO_IPV6, libc::IPV6_V6ONLY, &v6_only as *const _ as *const libc::c_void, mem::size_of::<libc::c_int>() as libc::socklen_t) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let (name, name_len) = ecn::from_socket_addr(&addr);
+    if unsafe { libc::bind(fd, &name as *const _ as *const libc::sockaddr, name_len) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(socket)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn bind_dual_stack(addr: SocketAddr) -> io::Result<UdpSocket> {
+    UdpSocket::bind(addr)
+}
+
+
+/// Drives protocol `State`s over a UDP socket: feeds them received datagrams and sends what they produce.
+/// The socket may be connected to the one peer it talks to, or serve several, each datagram then carries its
+/// destination. Uses batched I/O where the platform has it, one datagram per system call otherwise.
+pub struct UdpDriver {
+    pub socket: UdpSocket,
+    /// Datagrams can be marked, each with the codepoint of its connection.
+    ecn_marking: bool,
+    connected_peer: Option<SocketAddr>,
+    batch: Option<Batch>,
+    /// Datagrams the socket buffer had no room for, by peer and in order, sent first on the next flush.
+    queued: HashMap<SocketAddr, VecDeque<(EcnCodepoint, Vec<u8>)>>,
+}
+
+impl UdpDriver {
+    pub fn new(socket: UdpSocket) -> UdpDriver {
+        let ecn_marking = ecn::enable_ecn(&socket);
+        let connected_peer = socket.peer_addr().ok();
+        let batch = match Batch::new(&socket) {
+            Ok(batch) => {
+                info!("Batched I/O enabled, GSO: {}, GRO: {}", batch.gso_enabled(), batch.gro_enabled());
+                Some(batch)
+            }
+            Err(e) => {
+                info!("Batched I/O not available: {}", e);
+                None
+            }
+        };
+        UdpDriver { socket, ecn_marking, connected_peer, batch, queued: HashMap::new() }
+    }
+    pub fn ecn_enabled(&self) -> bool {
+        self.ecn_marking
+    }
+    /// Read one datagram, or a batch of them, handing each to `on_datagram` with its sender and ECN codepoint.
+    /// Returns whether there was any. An ICMP error for an earlier datagram is reported here as an error.
+    pub fn recv<F: FnMut(&[u8], SocketAddr, EcnCodepoint)>(&mut self, mut on_datagram: F) -> Result<bool> {
+        if let Some(batch) = &mut self.batch {
+            return match batch.recv(&self.socket, on_datagram) {
+                Ok(received) => Ok(received > 0),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(false),
+                Err(e) => Err(e.into()),
+            };
+        }
+        let mut buf = [0; 2000];
+        let (num_bytes_read, from, codepoint) = match ecn::recv_from(&self.socket, &mut buf) {
+            Ok(result) => result,
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+            Err(e) => return Err(e.into()),
+        };
+        on_datagram(&buf[..num_bytes_read], from, codepoint);
+        Ok(true)
+    }
+    /// Whether datagrams are waiting for room in the socket buffer, the socket is worth watching for it then.
+    pub fn has_queued(&self) -> bool {
+        !self.queued.is_empty()
+    }
+    /// Forget the datagrams still queued for `peer`.
+    pub fn discard(&mut self, peer: &SocketAddr) {
+        self.queued.remove(peer);
+    }
+    /// Send every datagram the state has produced, each marked with the ECN codepoint the state asks for, after
+    /// those queued for its peer earlier. What the socket buffer has no room for stays queued. If sending fails,
+    /// the datagrams not sent are lost.
+    pub fn flush(&mut self, state: &mut State) -> Result<()> {
+        let peer = match state.peer {
+            Some(peer) => peer,
+            None => return Ok(()),
+        };
+        let mut queue = self.queued.remove(&peer).unwrap_or_default();
+        while let Some(transmit) = state.poll_transmit(state.now()) {
+            let codepoint = match transmit.ecn {
+                Some(codepoint) if self.ecn_marking => codepoint,
+                _ => EcnCodepoint::NotEct,
+            };
+            if queue.len() >= MAX_QUEUED {
+                debug!("Send queue full, dropping packet of size {}.", transmit.contents.len());
+                continue;
+            }
+            debug!("Sending packet of size {}.", transmit.contents.len());
+            queue.push_back((codepoint, transmit.contents));
+        }
+        while let Some(&(codepoint, _)) = queue.front() {
+            // A run of datagrams with the same codepoint goes out in one batch.
+            let run = queue.iter().take_while(|(queued_codepoint, _)| *queued_codepoint == codepoint).count();
+            let datagrams: Vec<Vec<u8>> = queue.drain(..run).map(|(_, datagram)| datagram).collect();
+            let sent = self.send(peer, codepoint, &datagrams)?;
+            if sent < datagrams.len() {
+                debug!("Socket buffer full, queueing {} datagrams.", datagrams.len() - sent + queue.len());
+                for datagram in datagrams.into_iter().skip(sent).rev() { queue.push_front((codepoint, datagram)); }
+                self.queued.insert(peer, queue);
+                break;
+            }
+        }
+        Ok(())
+    }
+    /// Send the datagrams to the destination, marked with the codepoint, batched if possible. Returns how many
+    /// were sent before the socket buffer filled up.
+    fn send(&mut self, destination: SocketAddr, codepoint: EcnCodepoint, datagrams: &[Vec<u8>]) -> io::Result<usize> {
+        // A connected socket sends to its peer without naming it.
+        let destination = if Some(destination) == self.connected_peer { None } else { Some(destination) };
+        let socket = &self.socket;
+        if let Some(batch) = &mut self.batch {
+            return batch.send(socket, destination, codepoint, datagrams);
+        }
+        for (sent, datagram) in datagrams.iter().enumerate() {
+            match ecn::send_to(socket, datagram, destination, codepoint) {
+                Ok(_) => {}
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(sent),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(datagrams.len())
+    }
+}
@@ -0,0 +1,74 @@
+//! Batched sends on Linux: stopping where the socket buffer fills up so the rest can be sent later, and going
+//! back to one datagram per message when the kernel refuses to segment.
+#![cfg(target_os = "linux")]
+
+use PROJECT3::batch::Batch;
+use PROJECT3::ecn::EcnCodepoint;
+use std::mem;
+use std::net::UdpSocket;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::time::Duration;
+
+/// Both ends of a datagram socket pair. Not UDP, but `sendmmsg` takes it the same, and unlike over the loopback
+/// interface its buffer fills up while the other end does not read.
+fn socket_pair() -> (UdpSocket, UdpSocket) {
+    let mut fds = [0; 2];
+    assert_eq!(unsafe { libc::socketpair(libc::AF_UNIX, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0, fds.as_mut_ptr()) }, 0);
+    let (first, second) = unsafe { (UdpSocket::from_raw_fd(fds[0]), UdpSocket::from_raw_fd(fds[1])) };
+    first.set_nonblocking(true).unwrap();
+    second.set_nonblocking(true).unwrap();
+    (first, second)
+}
+
+fn datagrams(count: usize, size: usize) -> Vec<Vec<u8>> {
+    (0..count).map(|i| vec![i as u8; size]).collect()
+}
+
+/// Every datagram waiting on the socket.
+fn received(socket: &UdpSocket) -> Vec<Vec<u8>> {
+    let mut buf = [0; 2048];
+    let mut received = Vec::new();
+    while let Ok(length) = socket.recv(&mut buf) { received.push(buf[..length].to_vec()); }
+    received
+}
+
+#[test]
+fn stops_where_the_socket_buffer_fills_up() {
+    let (sender, receiver) = socket_pair();
+    let mut batch = Batch::new(&sender).unwrap();
+    let datagrams = datagrams(200, 1000);
+    let sent = batch.send(&sender, None, EcnCodepoint::NotEct, &datagrams).unwrap();
+    assert!(sent > 0 && sent < datagrams.len(), "sent {} of {}", sent, datagrams.len());
+    assert_eq!(received(&receiver), &datagrams[..sent]);
+    // Nothing goes out while the buffer is full, the rest does in order once there is room again.
+    let mut delivered = sent;
+    while delivered < datagrams.len() {
+        let sent = batch.send(&sender, None, EcnCodepoint::NotEct, &datagrams[delivered..]).unwrap();
+        assert!(sent > 0);
+        assert_eq!(batch.send(&sender, None, EcnCodepoint::NotEct, &datagrams[delivered + sent..]).unwrap(), 0);
+        assert_eq!(received(&receiver), &datagrams[delivered..delivered + sent]);
+        delivered += sent;
+    }
+}
+
+#[test]
+fn sends_one_datagram_per_message_once_segmentation_fails() {
+    let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+    sender.set_nonblocking(true).unwrap();
+    let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+    receiver.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+    let mut batch = Batch::new(&sender).unwrap();
+    if !batch.gso_enabled() { return; }
+    // The kernel refuses to segment for a socket that sends without UDP checksums.
+    let enable: libc::c_int = 1;
+    assert_eq!(unsafe { libc::setsockopt(sender.as_raw_fd(), libc::SOL_SOCKET, libc::SO_NO_CHECK, &enable as *const _ as *const libc::c_void, mem::size_of::<libc::c_int>() as libc::socklen_t) }, 0);
+
+    let datagrams = datagrams(10, 1000);
+    assert_eq!(batch.send(&sender, Some(receiver.local_addr().unwrap()), EcnCodepoint::NotEct, &datagrams).unwrap(), datagrams.len());
+    assert!(!batch.gso_enabled());
+    let mut buf = [0; 2048];
+    for datagram in datagrams.iter() {
+        let length = receiver.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..length], &datagram[..]);
+    }
+}
@@ -0,0 +1,100 @@
+//! Round trips of the wire format: anything serialized has to deserialize back to the same value.
+
+use PROJECT3::ecn::EcnCounts;
+use PROJECT3::protocol::{AckFrame, DataFrame, Frame, FrameType, Header, Packet, PacketType};
+use proptest::collection::vec;
+use proptest::prelude::*;
+
+fn header() -> impl Strategy<Value = Header> {
+    (0u8..4, any::<u64>()).prop_map(|(bits, packet_num)| Header { packet_type: PacketType::from_bits(bits).unwrap(), packet_num })
+}
+
+fn frame() -> impl Strategy<Value = Frame> {
+    (0u8..16, vec(any::<u8>(), 0..1500)).prop_map(|(bits, frame_data)| Frame { frame_type: FrameType::from_bits(bits).unwrap(), frame_data })
+}
+
+fn dataframe() -> impl Strategy<Value = DataFrame> {
+    (any::<bool>(), any::<u64>(), vec(any::<u8>(), 0..1500)).prop_map(|(end, byte_offset, data)| DataFrame { end, byte_offset, data })
+}
+
+fn ackframe() -> impl Strategy<Value = AckFrame> {
+    (any::<u64>(), any::<u64>(), vec(any::<u32>(), 0..32), any::<Option<(u64, u64, u64)>>()).prop_map(|(largest_ack, ack_delay, ack_ranges, ecn_counts)| AckFrame {
+        largest_ack,
+        ack_delay,
+        ack_ranges,
+        ecn_counts: ecn_counts.map(|(ect0, ect1, ce)| EcnCounts { ect0, ect1, ce }),
+    })
+}
+
+fn packet() -> impl Strategy<Value = Packet> {
+    (header(), vec(frame(), 0..4)).prop_map(|(header, frames)| Packet { header, frames })
+}
+
+/// Run lengths of ACKed and missing packets, going down from the largest ACKed one, as in `generate_ackframe`.
+fn ranges_of(acked: &[bool]) -> Vec<u32> {
+    let mut ranges = vec![0];
+    let mut flip = true;
+    for &packet_acked in acked {
+        if packet_acked != flip {
+            ranges.push(0);
+            flip = !flip;
+        }
+        *ranges.last_mut().unwrap() += 1;
+    }
+    ranges
+}
+
+proptest! {
+    #[test]
+    fn header_round_trip(header in header()) {
+        let bytes = header.serialize();
+        prop_assert_eq!(Header::deserialize(&bytes), Some((header, bytes.len())));
+    }
+
+    #[test]
+    fn frame_round_trip(frame in frame()) {
+        let bytes = frame.serialize();
+        prop_assert_eq!(Frame::deserialize(&bytes), Some((frame, bytes.len())));
+    }
+
+    #[test]
+    fn dataframe_round_trip(dataframe in dataframe()) {
+        prop_assert_eq!(DataFrame::deserialize(&dataframe.serialize()), Some(dataframe));
+    }
+
+    #[test]
+    fn ackframe_round_trip(ackframe in ackframe()) {
+        prop_assert_eq!(AckFrame::deserialize(&ackframe.serialize()), Some(ackframe));
+    }
+
+    #[test]
+    fn packet_round_trip(packet in packet()) {
+        prop_assert_eq!(Packet::deserialize(&packet.serialize()), Some(packet));
+    }
+
+    #[test]
+    fn is_acked_agrees_with_ranges(largest_ack in 1000..u64::MAX, below in vec(any::<bool>(), 0..200)) {
+        let mut acked = vec![true];
+        acked.extend(below);
+        let ackframe = AckFrame { largest_ack, ack_delay: 0, ack_ranges: ranges_of(&acked), ecn_counts: None };
+        for (i, &packet_acked) in acked.iter().enumerate() {
+            prop_assert_eq!(ackframe.is_acked(largest_ack - i as u64), packet_acked, "packet {} below the largest", i);
+        }
+        prop_assert!(!ackframe.is_acked(largest_ack + 1));
+        prop_assert!(!ackframe.is_acked(largest_ack - acked.len() as u64));
+        let ackframe = AckFrame::deserialize(&ackframe.serialize()).unwrap();
+        for (i, &packet_acked) in acked.iter().enumerate() {
+            prop_assert_eq!(ackframe.is_acked(largest_ack - i as u64), packet_acked, "packet {} below the largest after a round trip", i);
+        }
+    }
+}
+
+#[test]
+fn ackframe_rejects_ranges_too_long_to_hold() {
+    let ackframe = AckFrame { largest_ack: 10, ack_delay: 0, ack_ranges: vec![u32::MAX], ecn_counts: None };
+    let mut bytes = ackframe.serialize();
+    assert_eq!(AckFrame::deserialize(&bytes), Some(ackframe));
+    // The same range one past u32::MAX, as a five byte varint.
+    bytes[3..8].copy_from_slice(&[0x80, 0x80, 0x80, 0x80, 0x10]);
+    assert_eq!(AckFrame::deserialize(&bytes), None);
+}
@@ -0,0 +1,58 @@
+use clap::App;
+use PROJECT3::config::{self, ConfigError, ConnectionConfig};
+use std::time::Duration;
+
+#[test]
+fn builder_checks_the_settings_together() {
+    let config = ConnectionConfig::builder().max_datagram_size(1200).minimum_window(2400).initial_window(12000).build().unwrap();
+    assert_eq!(config.max_datagram_size, 1200);
+    assert_eq!(config.initial_window, 12000);
+    assert_eq!(config.max_PTO, ConnectionConfig::default().max_PTO);
+
+    let invalid = [
+        ConnectionConfig::builder().max_datagram_size(9000),
+        ConnectionConfig::builder().minimum_window(1472),
+        ConnectionConfig::builder().initial_window(10000),
+        ConnectionConfig::builder().send_buffer_capacity(0),
+        ConnectionConfig::builder().initial_PTO(Duration::from_secs(2)),
+        ConnectionConfig::builder().backoff_above_bandwidth(1.5),
+        ConnectionConfig::builder().backoff_below_bandwidth(0.0),
+    ];
+    for builder in invalid.iter() {
+        match builder.clone().build() {
+            Err(ConfigError::Invalid(_)) => {}
+            result => panic!("{:?} accepted: {:?}", builder, result),
+        }
+    }
+}
+
+#[test]
+fn toml_sets_what_it_names() {
+    let config = ConnectionConfig::from_toml("initial_window = 29440\nmax_pto_ms = 2000\nbackoff_above_bandwidth = 0.5\n").unwrap();
+    assert_eq!(config.initial_window, 29440);
+    assert_eq!(config.max_PTO, Duration::from_millis(2000));
+    assert_eq!(config.backoff_above_bandwidth, 0.5);
+    assert_eq!(config.minimum_window, ConnectionConfig::default().minimum_window);
+
+    assert!(matches!(ConnectionConfig::from_toml("initial_windw = 29440"), Err(ConfigError::Parse(_))));
+    assert!(matches!(ConnectionConfig::from_toml("initial_window = \"big\""), Err(ConfigError::Parse(_))));
+    assert!(matches!(ConnectionConfig::from_toml("initial_window = 100"), Err(ConfigError::Invalid(_))));
+}
+
+#[test]
+fn command_line_overrides_the_file() {
+    let path = std::env::temp_dir().join(format!("connection-config-{}.toml", std::process::id()));
+    std::fs::write(&path, "initial_window = 29440\nmax_ack_delay_ms = 10\n").unwrap();
+    let args = App::new("test").args(&config::args()).get_matches_from(vec![
+        "test", "--config", path.to_str().unwrap(), "--max-ack-delay-ms", "3", "--max-datagram-size", "1400",
+    ]);
+    let config = ConnectionConfig::from_args(&args);
+    std::fs::remove_file(&path).unwrap();
+    let config = config.unwrap();
+    assert_eq!(config.initial_window, 29440);
+    assert_eq!(config.max_ack_delay, Duration::from_millis(3));
+    assert_eq!(config.max_datagram_size, 1400);
+
+    let args = App::new("test").args(&config::args()).get_matches_from(vec!["test", "--initial-window", "lots"]);
+    assert!(matches!(ConnectionConfig::from_args(&args), Err(ConfigError::Parse(_))));
+}
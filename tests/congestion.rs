@@ -0,0 +1,142 @@
+//! Congestion control reacting to what the sender sees: idle periods decay the congestion window, a sender held
+//! back by pacing still grows it, the ECN counts of ACK frames are checked before CE marks are taken as
+//! congestion, and ACKs for packets never sent are ignored.
+
+use PROJECT3::clock::{Clock, MockClock};
+use PROJECT3::config::ConnectionConfig;
+use PROJECT3::ecn::EcnCounts;
+use PROJECT3::protocol::{AckFrame, State};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// An established sender with an RTT estimate, that has sent a little data and then nothing more.
+fn idle_sender(clock: &MockClock) -> State {
+    let mut state = State::with_clock(1, Some("127.0.0.1:28899".parse().unwrap()), ConnectionConfig::default(), Arc::new(clock.clone()));
+    state.established = true;
+    for _ in 0..2 { state.rtt.update(Duration::from_millis(100).as_nanos() as u64, 0, clock.now()); }
+    state.write_data(&[0; 1000]);
+    while state.poll_transmit(clock.now()).is_some() {}
+    state
+}
+
+#[test]
+fn idle_time_decays_the_window_once_per_rto() {
+    let clock = MockClock::new();
+    let mut state = idle_sender(&clock);
+    let initial_window = state.config.initial_window;
+    state.congestion_window = 16 * initial_window;
+    let rto = Duration::from_nanos(state.get_PTO());
+
+    clock.advance(rto * 3 / 2);
+    assert!(state.poll_transmit(clock.now()).is_none());
+    assert_eq!(state.congestion_window, 8 * initial_window);
+    // Polling again within the same RTO of idle time leaves the window alone.
+    clock.advance(rto / 4);
+    assert!(state.poll_transmit(clock.now()).is_none());
+    assert_eq!(state.congestion_window, 8 * initial_window);
+    // The next RTO of idle time halves it again, counted from where the last decay left off.
+    clock.advance(rto / 2);
+    assert!(state.poll_transmit(clock.now()).is_none());
+    assert_eq!(state.congestion_window, 4 * initial_window);
+    clock.advance(rto * 5);
+    assert!(state.poll_transmit(clock.now()).is_none());
+    assert_eq!(state.congestion_window, initial_window);
+}
+
+#[test]
+fn pacing_does_not_make_the_sender_app_limited() {
+    let clock = MockClock::new();
+    let mut state = State::with_clock(1, Some("127.0.0.1:28899".parse().unwrap()), ConnectionConfig::default(), Arc::new(clock.clone()));
+    state.established = true;
+    for _ in 0..2 { state.rtt.update(Duration::from_millis(100).as_nanos() as u64, 0, clock.now()); }
+    state.write_data(&[0; 100000]);
+    while state.poll_transmit(clock.now()).is_some() {}
+    let sent = state.last_packet_num;
+    state.on_ack_received(&AckFrame { largest_ack: sent, ack_delay: 0, ack_ranges: vec![sent as u32], ecn_counts: None }, clock.now());
+    // The window has room again, but the pacing budget was spent on the packets just acknowledged.
+    assert!(state.bytes_in_flight + state.config.max_datagram_size <= state.congestion_window);
+    assert!(state.pacing_tokens < state.config.max_datagram_size);
+    assert!(state.poll_transmit(clock.now()).is_none());
+    assert!(!state.app_limited);
+    // A probe going out meanwhile grows the window once acknowledged, like any data sent at the window's rate.
+    state.PTO_probes_pending = 1;
+    state.poll_transmit(clock.now()).unwrap();
+    let congestion_window = state.congestion_window;
+    state.on_ack_received(&AckFrame { largest_ack: sent + 1, ack_delay: 0, ack_ranges: vec![1], ecn_counts: None }, clock.now());
+    assert!(state.congestion_window > congestion_window);
+}
+
+/// An established sender marking ECT(0), with `packets` data packets in flight.
+fn ecn_sender(clock: &MockClock, packets: usize) -> State {
+    let mut state = State::with_clock(1, Some("127.0.0.1:28899".parse().unwrap()), ConnectionConfig::default(), Arc::new(clock.clone()));
+    state.established = true;
+    state.ecn_enabled = true;
+    for _ in 0..packets {
+        state.write_data(&[0; 100]);
+        state.poll_transmit(clock.now()).unwrap();
+    }
+    state
+}
+
+fn ack(largest_ack: u64, count: u32, ect0: u64, ect1: u64, ce: u64) -> AckFrame {
+    AckFrame { largest_ack, ack_delay: 0, ack_ranges: vec![count], ecn_counts: Some(EcnCounts { ect0, ect1, ce }) }
+}
+
+#[test]
+fn ecn_counts_matching_the_marks_keep_ecn_on() {
+    let clock = MockClock::new();
+    let mut state = ecn_sender(&clock, 4);
+    clock.advance(Duration::from_millis(50));
+    state.on_ack_received(&ack(2, 2, 2, 0, 0), clock.now());
+    assert!(state.ecn_enabled);
+    assert_eq!(state.ecn_peer_reported, EcnCounts { ect0: 2, ect1: 0, ce: 0 });
+    assert_eq!(state.congestion_recovery_start_time, None);
+    // A CE mark counts for an ECT(0) packet that arrived, and is a congestion event.
+    state.on_ack_received(&ack(4, 4, 3, 0, 1), clock.now());
+    assert!(state.ecn_enabled);
+    assert_eq!(state.congestion_recovery_start_time, Some(clock.now()));
+    assert_eq!(state.slow_start_threshold, state.congestion_window);
+}
+
+#[test]
+fn ecn_counts_that_do_not_add_up_turn_ecn_off() {
+    let invalid = vec![
+        ("no counts", AckFrame { largest_ack: 2, ack_delay: 0, ack_ranges: vec![2], ecn_counts: None }),
+        ("fewer marks than packets", ack(2, 2, 1, 0, 0)),
+        ("ECT(1) instead of ECT(0)", ack(2, 2, 2, 1, 0)),
+        ("counts overflowing", ack(2, 2, u64::MAX, 0, u64::MAX)),
+    ];
+    for (reason, ack_frame) in invalid {
+        let clock = MockClock::new();
+        let mut state = ecn_sender(&clock, 2);
+        state.on_ack_received(&ack_frame, clock.now());
+        assert!(!state.ecn_enabled, "{}", reason);
+        assert_eq!(state.congestion_recovery_start_time, None, "{}", reason);
+        // Packets from now on go out unmarked.
+        state.write_data(&[0; 100]);
+        assert_eq!(state.poll_transmit(clock.now()).unwrap().ecn, None, "{}", reason);
+    }
+
+    // Counts going down on a later ACK.
+    let clock = MockClock::new();
+    let mut state = ecn_sender(&clock, 4);
+    state.on_ack_received(&ack(2, 2, 2, 0, 0), clock.now());
+    state.on_ack_received(&ack(4, 4, 1, 0, 0), clock.now());
+    assert!(!state.ecn_enabled);
+}
+
+#[test]
+fn ack_of_packets_never_sent_is_ignored() {
+    let clock = MockClock::new();
+    let mut state = ecn_sender(&clock, 2);
+    let forged = AckFrame { largest_ack: 100, ack_delay: 0, ack_ranges: vec![100], ecn_counts: None };
+    state.on_ack_received(&forged, clock.now());
+    assert_eq!(state.sent_largest_ACKed, 0);
+    assert_eq!(state.sent_packets.len(), 2);
+    // Packets sent afterwards are not taken as lost by the packet threshold.
+    state.write_data(&[0; 100]);
+    state.poll_transmit(clock.now()).unwrap();
+    state.on_ack_received(&ack(2, 2, 2, 0, 0), clock.now());
+    assert_eq!(state.sent_packets.keys().collect::<Vec<_>>(), vec![&3]);
+    assert!(state.send_state.lost_data.is_empty());
+}
@@ -0,0 +1,218 @@
+//! `Connection` and `Listener` over real UDP sockets on the loopback interface, each receiver in a thread of its
+//! own. The receivers hand their connection back, so the socket stays open until the senders are done closing.
+
+use PROJECT3::config::ConnectionConfig;
+use PROJECT3::connection::Connection;
+use PROJECT3::error::Error;
+use PROJECT3::listener::Listener;
+use PROJECT3::protocol::{Frame, FrameType, Header, Packet, PacketType};
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::mpsc;
+use std::thread;
+
+fn test_data(size: usize) -> Vec<u8> {
+    (0..size).map(|i| (i * 7919 % 251) as u8).collect()
+}
+
+#[test]
+fn writes_arrive_in_order_and_end_the_stream() {
+    let data = test_data(300000);
+    let mut listener = Listener::bind("127.0.0.1:0", ConnectionConfig::default()).unwrap();
+    let addr = listener.local_addr().unwrap();
+    let receiver = thread::spawn(move || {
+        let mut connection = listener.accept().unwrap();
+        let mut received = Vec::new();
+        connection.read_to_end(&mut received).unwrap();
+        connection.close().unwrap();
+        (received, connection)
+    });
+
+    let mut connection = Connection::connect(addr, ConnectionConfig::default()).unwrap();
+    assert_eq!(connection.peer_addr(), addr);
+    for chunk in data.chunks(10000) {
+        connection.write_all(chunk).unwrap();
+    }
+    connection.flush().unwrap();
+    connection.close().unwrap();
+    assert!(connection.write(b"more").is_err());
+    assert!(receiver.join().unwrap().0 == data);
+}
+
+#[test]
+fn nonblocking_write_stops_at_a_full_send_buffer() {
+    let data = test_data(20000);
+    let mut listener = Listener::bind("127.0.0.1:0", ConnectionConfig::default()).unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (start_reading, may_read) = mpsc::channel();
+    let receiver = thread::spawn(move || {
+        let mut connection = listener.accept().unwrap();
+        may_read.recv().unwrap();
+        let mut received = Vec::new();
+        connection.read_to_end(&mut received).unwrap();
+        connection.close().unwrap();
+        (received, connection)
+    });
+
+    let config = ConnectionConfig::builder().send_buffer_capacity(4096).build().unwrap();
+    let mut connection = Connection::connect(addr, config).unwrap();
+    connection.set_nonblocking(true);
+    assert_eq!(connection.write(&data).unwrap(), 4096);
+    assert_eq!(connection.write(&data[4096..]).unwrap_err().kind(), io::ErrorKind::WouldBlock);
+
+    start_reading.send(()).unwrap();
+    let mut written = 4096;
+    while written < data.len() {
+        match connection.write(&data[written..]) {
+            Ok(length) => written += length,
+            Err(ref error) if error.kind() == io::ErrorKind::WouldBlock => connection.wait().unwrap(),
+            Err(error) => panic!("write failed: {}", error),
+        }
+    }
+    connection.set_nonblocking(false);
+    connection.close().unwrap();
+    assert!(receiver.join().unwrap().0 == data);
+}
+
+#[test]
+fn listener_serves_several_senders_at_once() {
+    let mut listener = Listener::bind("127.0.0.1:0", ConnectionConfig::default()).unwrap();
+    let addr = listener.local_addr().unwrap();
+    let senders: Vec<_> = (0..4).map(|i| thread::spawn(move || {
+        let data = test_data(100000 + i * 1000);
+        let mut connection = Connection::connect(addr, ConnectionConfig::default()).unwrap();
+        connection.write_all(&data).unwrap();
+        connection.close().unwrap();
+        (connection.local_addr().unwrap(), data)
+    })).collect();
+
+    let receivers: Vec<_> = (0..4).map(|_| {
+        let mut connection = listener.accept().unwrap();
+        thread::spawn(move || {
+            let mut received = Vec::new();
+            connection.read_to_end(&mut received).unwrap();
+            connection.close().unwrap();
+            (connection.peer_addr(), received, connection)
+        })
+    }).collect();
+    let mut received: Vec<_> = receivers.into_iter().map(|receiver| receiver.join().unwrap()).collect();
+    for sender in senders {
+        let (sender_addr, data) = sender.join().unwrap();
+        let index = received.iter().position(|(peer, _, _)| *peer == sender_addr).expect("sender never accepted");
+        assert!(received.remove(index).1 == data);
+    }
+}
+
+#[test]
+fn listener_ignores_forged_packets_numbered_0() {
+    let data = test_data(50000);
+    let mut listener = Listener::bind("127.0.0.1:0", ConnectionConfig::default()).unwrap();
+    let addr = listener.local_addr().unwrap();
+    // An INIT numbered 0 that asks for an ACK and a CLOSE in return.
+    let forged = Packet {
+        header: Header { packet_type: PacketType::INIT, packet_num: 0 },
+        frames: vec![Frame { frame_type: FrameType::CLOSE, frame_data: vec![0] }],
+    };
+    let forger = UdpSocket::bind("127.0.0.1:0").unwrap();
+    forger.send_to(&forged.serialize(), addr).unwrap();
+    let sender = thread::spawn(move || {
+        let mut connection = Connection::connect(addr, ConnectionConfig::default()).unwrap();
+        connection.write_all(&data).unwrap();
+        connection.close().unwrap();
+        (connection.local_addr().unwrap(), data)
+    });
+
+    let mut connection = listener.accept().unwrap();
+    let mut received = Vec::new();
+    connection.read_to_end(&mut received).unwrap();
+    connection.close().unwrap();
+    let (sender_addr, data) = sender.join().unwrap();
+    assert_eq!(connection.peer_addr(), sender_addr);
+    assert!(received == data);
+}
+
+#[test]
+fn listener_drops_packets_numbered_far_ahead() {
+    let data = test_data(50000);
+    let mut listener = Listener::bind("127.0.0.1:0", ConnectionConfig::default()).unwrap();
+    let addr = listener.local_addr().unwrap();
+    // A handshake, then packets whose numbers every ACK would have to walk up to.
+    let forger = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let ping = || vec![Frame { frame_type: FrameType::PING, frame_data: vec![0] }];
+    for &(packet_type, packet_num) in &[(PacketType::INIT, 5), (PacketType::NORM, 1 << 34), (PacketType::NORM, u64::MAX), (PacketType::INIT, u64::MAX)] {
+        let forged = Packet { header: Header { packet_type, packet_num }, frames: ping() };
+        forger.send_to(&forged.serialize(), addr).unwrap();
+    }
+    let sender = thread::spawn(move || {
+        let mut connection = Connection::connect(addr, ConnectionConfig::default()).unwrap();
+        connection.write_all(&data).unwrap();
+        connection.close().unwrap();
+        data
+    });
+
+    let mut connection = listener.accept().unwrap();
+    let mut received = Vec::new();
+    connection.read_to_end(&mut received).unwrap();
+    connection.close().unwrap();
+    assert!(received == sender.join().unwrap());
+}
+
+#[test]
+fn connecting_where_nothing_listens_is_refused() {
+    // The port was just free, and nothing took it since.
+    let addr = UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    match Connection::connect(addr, ConnectionConfig::default()) {
+        Err(Error::ConnectionRefused) => {}
+        Err(error) => panic!("connect failed with {}", error),
+        Ok(_) => panic!("connected to nothing"),
+    }
+}
+
+#[test]
+fn sender_closes_cleanly_after_the_receiver_is_gone() {
+    let data = test_data(50000);
+    let mut listener = Listener::bind("127.0.0.1:0", ConnectionConfig::default()).unwrap();
+    let addr = listener.local_addr().unwrap();
+    let receiver = thread::spawn(move || {
+        let mut connection = listener.accept().unwrap();
+        let mut received = Vec::new();
+        connection.read_to_end(&mut received).unwrap();
+        connection.close().unwrap();
+        received
+    });
+
+    let mut connection = Connection::connect(addr, ConnectionConfig::default()).unwrap();
+    connection.write_all(&data).unwrap();
+    connection.shutdown().unwrap();
+    connection.flush().unwrap();
+    assert!(receiver.join().unwrap() == data);
+    connection.close().unwrap();
+}
+
+#[test]
+fn ipv6_listener_takes_ipv4_senders_too() {
+    let mut listener = Listener::bind("[::]:0", ConnectionConfig::default()).unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let senders: Vec<_> = ["127.0.0.1", "::1"].iter().map(|ip| {
+        let addr = SocketAddr::new(ip.parse().unwrap(), port);
+        thread::spawn(move || {
+            let data = test_data(50000);
+            let mut connection = Connection::connect(addr, ConnectionConfig::default()).unwrap();
+            connection.write_all(&data).unwrap();
+            connection.close().unwrap();
+            data
+        })
+    }).collect();
+
+    for _ in 0..2 {
+        let mut connection = listener.accept().unwrap();
+        assert!(connection.peer_addr().is_ipv6());
+        let mut received = Vec::new();
+        connection.read_to_end(&mut received).unwrap();
+        connection.close().unwrap();
+        assert!(received == test_data(50000));
+    }
+    for sender in senders {
+        sender.join().unwrap();
+    }
+}
@@ -0,0 +1,96 @@
+//! The range set behind the sender's acknowledged and lost bytes, and the retransmissions built from it: lost
+//! bytes go out again packed together, lowest offset first, and never once acknowledged in another packet.
+
+use PROJECT3::clock::{Clock, MockClock};
+use PROJECT3::config::ConnectionConfig;
+use PROJECT3::protocol::{AckFrame, DataFrame, FrameType, Packet, SentPacket, State};
+use PROJECT3::range_set::RangeSet;
+use proptest::collection::vec;
+use proptest::prelude::*;
+use std::sync::Arc;
+
+#[test]
+fn merges_and_splits() {
+    let mut set = RangeSet::new();
+    set.insert(10, 20);
+    set.insert(30, 40);
+    set.insert(20, 30);
+    assert_eq!(set.iter().collect::<Vec<_>>(), vec![(10, 40)]);
+    set.remove(15, 25);
+    set.remove(35, 50);
+    assert_eq!(set.iter().collect::<Vec<_>>(), vec![(10, 15), (25, 35)]);
+    assert_eq!(set.bytes(), 15);
+    set.remove(0, 100);
+    assert!(set.is_empty());
+}
+
+/// A sender that has written and sent `writes` chunks of 100 bytes, each in its own packet.
+fn sender_with_small_packets(clock: &MockClock, writes: usize) -> (State, Vec<u64>) {
+    let mut state = State::with_clock(1, Some("127.0.0.1:28899".parse().unwrap()), ConnectionConfig::default(), Arc::new(clock.clone()));
+    state.established = true;
+    let mut packet_nums = Vec::new();
+    for i in 0..writes {
+        state.write_data(&[i as u8; 100]);
+        while state.poll_transmit(clock.now()).is_some() {}
+        packet_nums.push(state.last_packet_num);
+    }
+    (state, packet_nums)
+}
+
+fn lose(state: &mut State, packet_nums: &[u64], clock: &MockClock) {
+    let lost: Vec<SentPacket> = packet_nums.iter().map(|packet_num| state.sent_packets.remove(packet_num).unwrap()).collect();
+    state.on_packets_lost(lost, clock.now());
+}
+
+/// The byte ranges of the DATA frames in the datagrams sent since the last call.
+fn sent_ranges(state: &mut State, clock: &MockClock) -> Vec<Vec<(u64, u64)>> {
+    let mut packets = Vec::new();
+    while let Some(transmit) = state.poll_transmit(clock.now()) {
+        let packet = Packet::deserialize(&transmit.contents).unwrap();
+        packets.push(packet.frames.iter().filter(|frame| frame.frame_type == FrameType::DATA).map(|frame| {
+            let dataframe = DataFrame::deserialize(&frame.frame_data).unwrap();
+            (dataframe.byte_offset, dataframe.byte_offset + dataframe.data.len() as u64)
+        }).collect());
+    }
+    packets
+}
+
+#[test]
+fn packs_lost_segments_into_one_packet() {
+    let clock = MockClock::new();
+    let (mut state, packet_nums) = sender_with_small_packets(&clock, 5);
+    lose(&mut state, &[packet_nums[0], packet_nums[1], packet_nums[3]], &clock);
+    assert_eq!(sent_ranges(&mut state, &clock), vec![vec![(0, 200), (300, 400)]]);
+}
+
+#[test]
+fn never_resends_acknowledged_bytes() {
+    let clock = MockClock::new();
+    let (mut state, packet_nums) = sender_with_small_packets(&clock, 5);
+    // A probe carries the bytes of the first packet again and is acknowledged, which declares the first two lost.
+    state.PTO_probes_pending = 1;
+    assert_eq!(sent_ranges(&mut state, &clock), vec![vec![(0, 100)]]);
+    let probe = state.last_packet_num;
+    state.on_ack_received(&AckFrame { largest_ack: probe, ack_delay: 0, ack_ranges: vec![1], ecn_counts: None }, clock.now());
+    assert!(!state.sent_packets.contains_key(&packet_nums[0]));
+    assert_eq!(sent_ranges(&mut state, &clock), vec![vec![(100, 200)]]);
+}
+
+proptest! {
+    #[test]
+    fn matches_a_set_of_bytes(operations in vec((any::<bool>(), 0..200u64, 0..50u64), 1..60)) {
+        let mut set = RangeSet::new();
+        let mut bytes = [false; 250];
+        for (insert, start, length) in operations.iter() {
+            if *insert { set.insert(*start, start + length); } else { set.remove(*start, start + length); }
+            for byte in bytes[*start as usize..(start + length) as usize].iter_mut() { *byte = *insert; }
+            let ranges: Vec<(u64, u64)> = set.iter().collect();
+            for pair in ranges.windows(2) {
+                prop_assert!(pair[0].1 < pair[1].0, "ranges overlap or touch: {:?}", ranges);
+            }
+            let expected: Vec<u64> = (0..250).filter(|offset| bytes[*offset as usize]).collect();
+            let actual: Vec<u64> = ranges.iter().flat_map(|(start, end)| *start..*end).collect();
+            prop_assert_eq!(actual, expected);
+        }
+    }
+}
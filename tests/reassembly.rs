@@ -0,0 +1,141 @@
+//! The reassembly buffer has to rebuild the stream from segments in any order, duplicated, and split at
+//! different offsets on retransmission, and the receiver only takes segments within its window.
+
+use PROJECT3::config::ConnectionConfig;
+use PROJECT3::protocol::{DataFrame, Frame, FrameType, Header, Packet, PacketType, State};
+use PROJECT3::reassembly::{Conflict, ReassemblyBuffer};
+use proptest::collection::vec;
+use proptest::prelude::*;
+use std::time::Instant;
+
+fn read_all(buffer: &mut ReassemblyBuffer) -> Vec<u8> {
+    let mut output = Vec::new();
+    while let Some(data) = buffer.read() {
+        output.extend(data);
+    }
+    output
+}
+
+#[test]
+fn merges_adjacent_ranges() {
+    let mut buffer = ReassemblyBuffer::new();
+    assert_eq!(buffer.insert(4, b"efgh"), Ok(4));
+    assert_eq!(buffer.insert(12, b"mnop"), Ok(4));
+    assert_eq!(buffer.insert(8, b"ijkl"), Ok(4));
+    assert_eq!(buffer.ranges(), vec![(4, 16)]);
+    assert_eq!(buffer.read(), None);
+    assert_eq!(buffer.insert(0, b"abcd"), Ok(4));
+    assert_eq!(buffer.read(), Some(b"abcdefghijklmnop".to_vec()));
+    assert_eq!(buffer.read_offset(), 16);
+}
+
+#[test]
+fn trims_resplit_retransmissions() {
+    let data = b"0123456789abcdefghij";
+    let mut buffer = ReassemblyBuffer::new();
+    // First sent as 0..8 and 8..20, the second packet is lost and re-packaged as 5..12 and 12..20.
+    assert_eq!(buffer.insert(0, &data[0..8]), Ok(8));
+    assert_eq!(buffer.insert(5, &data[5..12]), Ok(4));
+    assert_eq!(buffer.insert(12, &data[12..20]), Ok(8));
+    assert_eq!(buffer.insert(8, &data[8..20]), Ok(0));
+    assert_eq!(read_all(&mut buffer), data.to_vec());
+}
+
+#[test]
+fn drops_data_already_read() {
+    let mut buffer = ReassemblyBuffer::new();
+    buffer.insert(0, b"abcd").unwrap();
+    assert_eq!(read_all(&mut buffer), b"abcd".to_vec());
+    assert_eq!(buffer.insert(0, b"ab"), Ok(0));
+    assert_eq!(buffer.insert(2, b"cdef"), Ok(2));
+    assert_eq!(read_all(&mut buffer), b"ef".to_vec());
+    assert_eq!(buffer.buffered(), 0);
+}
+
+#[test]
+fn detects_conflicting_bytes() {
+    let mut buffer = ReassemblyBuffer::new();
+    buffer.insert(0, b"abcd").unwrap();
+    buffer.insert(8, b"ijkl").unwrap();
+    assert_eq!(buffer.insert(2, b"cX"), Err(Conflict { offset: 3 }));
+    assert_eq!(buffer.insert(6, b"ghiX"), Err(Conflict { offset: 9 }));
+    // Nothing of a conflicting segment is kept.
+    assert_eq!(buffer.ranges(), vec![(0, 4), (8, 12)]);
+}
+
+#[test]
+fn drops_segments_past_the_largest_offset() {
+    let mut buffer = ReassemblyBuffer::new();
+    buffer.insert(0, b"abcd").unwrap();
+    assert_eq!(buffer.insert(u64::MAX - 1, b"xyz"), Ok(0));
+    assert_eq!(buffer.insert(u64::MAX - 3, b"xyz"), Ok(3));
+    assert_eq!(buffer.ranges(), vec![(0, 4), (u64::MAX - 3, u64::MAX)]);
+
+    // A forged end of the stream there is dropped by the receiver too.
+    let mut state = State::new(1, None, ConnectionConfig::default());
+    state.on_data_received(&DataFrame { end: true, byte_offset: u64::MAX - 1, data: b"xyz".to_vec() });
+    assert_eq!(state.receive_state.end_received, None);
+    assert!(state.receive_state.received_data.ranges().is_empty());
+}
+
+#[test]
+fn receiver_drops_data_past_the_receive_window() {
+    let config = ConnectionConfig::builder().send_buffer_capacity(1000).build().unwrap();
+    let mut state = State::new(1, None, config);
+    let from = "127.0.0.1:29170".parse().unwrap();
+    let now = Instant::now();
+    let packet = |packet_num, byte_offset| {
+        let dataframe = DataFrame { end: false, byte_offset, data: b"abcd".to_vec() };
+        Packet { header: Header { packet_type: PacketType::NORM, packet_num }, frames: vec![Frame { frame_type: FrameType::DATA, frame_data: dataframe.serialize() }] }.serialize()
+    };
+    // Neither stored nor acknowledged.
+    assert!(!state.handle_datagram(&packet(1, u64::MAX - 4), from, now));
+    assert!(!state.handle_datagram(&packet(2, 1001), from, now));
+    assert!(state.received_packets.is_empty());
+    assert!(state.receive_state.received_data.ranges().is_empty());
+    // The window reaches as far past the first gap as the sender's buffer, and moves on as the gap is filled.
+    assert!(state.handle_datagram(&packet(3, 1000), from, now));
+    assert!(!state.handle_datagram(&packet(4, 1005), from, now));
+    assert!(state.handle_datagram(&packet(5, 0), from, now));
+    assert!(state.handle_datagram(&packet(6, 1004), from, now));
+    assert_eq!(state.receive_state.received_data.ranges(), vec![(0, 4), (1000, 1008)]);
+}
+
+proptest! {
+    #[test]
+    fn reassembles_any_split(
+        data in vec(any::<u8>(), 1..3000),
+        cuts in vec((any::<prop::sample::Index>(), any::<prop::sample::Index>()), 1..40),
+        swaps in vec((any::<prop::sample::Index>(), any::<prop::sample::Index>()), 0..80),
+    ) {
+        // Cover the data once with in-order segments, then add arbitrary overlapping ones and shuffle them all.
+        let mut segments: Vec<(usize, usize)> = data.chunks(500).enumerate().map(|(i, chunk)| (i * 500, i * 500 + chunk.len())).collect();
+        for (a, b) in cuts.iter() {
+            let (a, b) = (a.index(data.len() + 1), b.index(data.len() + 1));
+            segments.push((a.min(b), a.max(b)));
+        }
+        for (a, b) in swaps.iter() {
+            let (a, b) = (a.index(segments.len()), b.index(segments.len()));
+            segments.swap(a, b);
+        }
+
+        let mut buffer = ReassemblyBuffer::new();
+        let mut output = Vec::new();
+        let mut new_bytes = 0;
+        for (i, (start, end)) in segments.iter().enumerate() {
+            new_bytes += buffer.insert(*start as u64, &data[*start..*end]).unwrap();
+            // Read as we go every now and then, like the receiver does once the end is known.
+            if i % 3 == 0 {
+                while let Some(bytes) = buffer.read() { output.extend(bytes); }
+            }
+            let ranges = buffer.ranges();
+            for pair in ranges.windows(2) {
+                prop_assert!(pair[0].1 < pair[1].0, "ranges overlap or touch: {:?}", ranges);
+            }
+        }
+        while let Some(bytes) = buffer.read() { output.extend(bytes); }
+        prop_assert_eq!(new_bytes, data.len());
+        prop_assert_eq!(output, data);
+        prop_assert_eq!(buffer.buffered(), 0);
+    }
+}
@@ -0,0 +1,41 @@
+//! The windowed filters behind min and max RTT: a sample is kept for one window, and when it ages out the best
+//! samples of the later parts of the window take over instead of the next one that happens to arrive.
+
+use PROJECT3::rtt::WindowedFilter;
+use std::time::{Duration, Instant};
+
+#[test]
+fn forgets_samples_older_than_the_window() {
+    let start = Instant::now();
+    let mut min = WindowedFilter::new_min(Duration::from_secs(10));
+    assert_eq!(min.update(100, start), 100);
+    assert_eq!(min.update(200, start + Duration::from_secs(9)), 100);
+    assert_eq!(min.update(300, start + Duration::from_secs(11)), 200);
+    assert_eq!(min.update(400, start + Duration::from_secs(22)), 400);
+
+    let mut max = WindowedFilter::new_max(Duration::from_secs(10));
+    assert_eq!(max.update(400, start), 400);
+    assert_eq!(max.update(100, start + Duration::from_secs(5)), 400);
+    assert_eq!(max.update(50, start + Duration::from_secs(16)), 50);
+}
+
+#[test]
+fn promotes_the_best_samples_of_later_sub_windows() {
+    let start = Instant::now();
+    let at = |millis| start + Duration::from_millis(millis);
+    let mut min = WindowedFilter::new_min(Duration::from_secs(4));
+    assert_eq!(min.update(100, at(0)), 100);
+    // Past a quarter of the window the second best is replaced, past half of it the third best.
+    assert_eq!(min.update(150, at(1500)), 100);
+    assert_eq!(min.update(170, at(2500)), 100);
+    // A sample better than the second best replaces it and the third best.
+    assert_eq!(min.update(120, at(3000)), 100);
+    assert_eq!(min.get(), 100);
+    // The best sample ages out, the second best of the window takes over, then the third best.
+    assert_eq!(min.update(200, at(4500)), 120);
+    assert_eq!(min.update(210, at(7500)), 200);
+    // A better sample replaces all three at once.
+    assert_eq!(min.update(90, at(7600)), 90);
+    assert_eq!(min.update(95, at(11000)), 90);
+    assert_eq!(min.update(99, at(11700)), 95);
+}
@@ -0,0 +1,39 @@
+//! The send buffer is a window over the stream: bytes are written at the end, read back at any offset still
+//! held, and released from the start once ACKed.
+
+use PROJECT3::send_buffer::SendBuffer;
+
+#[test]
+fn bounded_by_capacity() {
+    let mut buffer = SendBuffer::new(8);
+    assert_eq!(buffer.write(b"abcdef"), 6);
+    assert_eq!(buffer.write(b"ghijkl"), 2);
+    assert_eq!(buffer.free_space(), 0);
+    buffer.release(3);
+    assert_eq!(buffer.free_space(), 3);
+    assert_eq!(buffer.write(b"ijkl"), 3);
+    assert_eq!((buffer.start(), buffer.end()), (3, 11));
+}
+
+#[test]
+fn reads_across_the_wrap() {
+    let mut buffer = SendBuffer::new(8);
+    buffer.write(b"abcdefgh");
+    buffer.release(6);
+    buffer.write(b"ijklmn");
+    assert_eq!(buffer.get(6, 8), b"ghijklmn".to_vec());
+    assert_eq!(buffer.get(7, 3), b"hij".to_vec());
+    // Past the end the read is cut short.
+    assert_eq!(buffer.get(12, 10), b"mn".to_vec());
+    assert_eq!(buffer.get(14, 10), Vec::<u8>::new());
+}
+
+#[test]
+fn nothing_written_after_finish() {
+    let mut buffer = SendBuffer::new(8);
+    buffer.write(b"abc");
+    buffer.finish();
+    assert!(buffer.is_finished());
+    assert_eq!(buffer.write(b"def"), 0);
+    assert_eq!(buffer.end(), 3);
+}